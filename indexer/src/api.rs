@@ -1,70 +1,130 @@
 use crate::{
     config::Config,
     database::Database,
-    models::{HistoricalPosition, PaginatedResponse},
+    models::{HistoricalPosition, HistoryCursor, PaginatedResponse, SyncStatus},
 };
 use anyhow::Result;
 use axum::{
-    extract::{Path, Query, State},
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
     http::{HeaderMap, StatusCode},
-    response::Json,
+    response::{Json, Response},
     routing::get,
     Router,
 };
-// Aptos uses different signature verification - will implement later
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, VerifyingKey};
+use reqwest::Client;
 use serde::Deserialize;
 use serde_json::Value;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{broadcast, watch};
 use tower_http::cors::{Any, CorsLayer};
 
 // The shared state for our Axum handlers
 type AppState = State<Arc<Database>>;
 
+fn auth_header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, StatusCode> {
+    headers
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .ok_or(StatusCode::UNAUTHORIZED)
+}
+
+fn decode_hex<const N: usize>(value: &str) -> Result<[u8; N], StatusCode> {
+    let bytes = hex::decode(value.strip_prefix("0x").unwrap_or(value))
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    bytes.try_into().map_err(|_| StatusCode::UNAUTHORIZED)
+}
+
+// Verifies a hex Ed25519 signature over `message` against a hex public key,
+// returning the verified 32-byte public key.
+fn verify_signature(signature: &str, message: &str, public_key: &str) -> Result<[u8; 32], StatusCode> {
+    let signature_bytes: [u8; 64] = decode_hex(signature)?;
+    let pub_key: [u8; 32] = decode_hex(public_key)?;
+
+    let verifying_key = VerifyingKey::from_bytes(&pub_key).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let signature = Signature::from_bytes(&signature_bytes);
+    verifying_key
+        .verify_strict(message.as_bytes(), &signature)
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    Ok(pub_key)
+}
+
+// Verifies the Ed25519 signature in `x-signature` over `x-message` against the
+// key in `x-public-key`, returning the verified 32-byte public key.
 async fn check_auth(headers: &HeaderMap) -> Result<[u8; 32], StatusCode> {
-    // println!("[AUTH] Starting authentication check");
+    verify_signature(
+        auth_header(headers, "x-signature")?,
+        auth_header(headers, "x-message")?,
+        auth_header(headers, "x-public-key")?,
+    )
+}
 
-    let sig_header = headers
-        .get("x-signature")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| {
-            // println!("[AUTH] Error: x-signature header missing or invalid");
-            StatusCode::UNAUTHORIZED
-        })?;
-    // println!("[AUTH] Found x-signature header");
+// Signed messages older (or further in the future) than this are rejected.
+const AUTH_MESSAGE_MAX_AGE_SECONDS: i64 = 60;
 
-    let msg_header = headers
-        .get("x-message")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| {
-            // println!("[AUTH] Error: x-message header missing or invalid");
-            StatusCode::UNAUTHORIZED
-        })?;
-    // println!("[AUTH] Found x-message header");
-
-    // TODO: Implement Aptos Ed25519 signature verification
-    // For now, use a simple hash of the message as the public key
-    // This is a temporary solution - proper Ed25519 verification should be implemented
-    let pub_key_bytes = msg_header.as_bytes();
-    let mut pub_key = [0u8; 32];
-    
-    // Simple hash using the first 32 bytes or pad with zeros
-    if pub_key_bytes.len() >= 32 {
-        pub_key.copy_from_slice(&pub_key_bytes[..32]);
-    } else {
-        pub_key[..pub_key_bytes.len()].copy_from_slice(pub_key_bytes);
+// The envelope clients must sign in `x-message` for private routes, e.g.
+// {"nonce":42,"timestamp":"2024-01-01T00:00:00Z"}. The nonce must increase
+// with every request made by the same key.
+#[derive(Debug, Deserialize)]
+struct AuthEnvelope {
+    nonce: u64,
+    timestamp: DateTime<Utc>,
+}
+
+fn parse_auth_envelope(message: &str, now: DateTime<Utc>) -> Result<AuthEnvelope, StatusCode> {
+    let envelope: AuthEnvelope =
+        serde_json::from_str(message).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let age = now.signed_duration_since(envelope.timestamp).num_seconds();
+    if age.abs() > AUTH_MESSAGE_MAX_AGE_SECONDS {
+        return Err(StatusCode::UNAUTHORIZED);
     }
-    // println!("[AUTH] Public key derived: {:?}", hex::encode(pub_key));
+    Ok(envelope)
+}
 
-    // println!("[AUTH] Authentication check successful");
+// Like `check_auth`, but additionally requires the signed message to be a fresh
+// `AuthEnvelope` whose nonce has not been used by this key before.
+async fn check_auth_with_replay_protection(
+    db: &Database,
+    headers: &HeaderMap,
+) -> Result<[u8; 32], StatusCode> {
+    let pub_key = check_auth(headers).await?;
+    consume_auth_envelope(db, &pub_key, auth_header(headers, "x-message")?)?;
     Ok(pub_key)
 }
 
+// Accepts `message` only if it is a fresh `AuthEnvelope` carrying a nonce not
+// yet used by `pub_key`.
+fn consume_auth_envelope(db: &Database, pub_key: &[u8; 32], message: &str) -> Result<(), StatusCode> {
+    let envelope = parse_auth_envelope(message, Utc::now())?;
+    let fresh = db
+        .consume_auth_nonce(pub_key, envelope.nonce)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !fresh {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct PaginationParams {
-    cursor: Option<usize>,
+    cursor: Option<String>,
     page_size: Option<usize>,
 }
 
+impl PaginationParams {
+    fn history_cursor(&self) -> Result<Option<HistoryCursor>, StatusCode> {
+        self.cursor
+            .as_deref()
+            .map(|token| HistoryCursor::decode(token).ok_or(StatusCode::BAD_REQUEST))
+            .transpose()
+    }
+}
+
 // GET /positions/{positionId}
 async fn get_position_by_id(
     State(db): AppState,
@@ -82,12 +142,44 @@ async fn get_position_by_id(
     }
 }
 
+// GET /notes/{noteId}
+async fn get_note_by_id(
+    State(db): AppState,
+    Path(note_id_str): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    let note_id_bytes = hex::decode(
+        note_id_str.strip_prefix("0x").unwrap_or(&note_id_str)
+    ).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    match db.get_unspent_note(&note_id_bytes) {
+        Ok(Some(note)) => Ok(Json(serde_json::json!({ "note": note }))),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// GET /positions/{positionId}/events
+async fn get_position_events(
+    State(db): AppState,
+    Path(position_id_str): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    let position_id_bytes = hex::decode(
+        position_id_str.strip_prefix("0x").unwrap_or(&position_id_str)
+    ).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    match db.get_position_events(&position_id_bytes) {
+        Ok(events) if events.is_empty() => Err(StatusCode::NOT_FOUND),
+        Ok(events) => Ok(Json(serde_json::json!({ "events": events }))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
 // GET /positions/open
 async fn get_private_open_positions(
     State(db): AppState,
     headers: HeaderMap,
 ) -> Result<Json<Value>, StatusCode> {
-    let owner_pub_key = check_auth(&headers).await?;
+    let owner_pub_key = check_auth_with_replay_protection(&db, &headers).await?;
     let positions = db
         .get_open_positions(&owner_pub_key)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -101,11 +193,12 @@ async fn get_private_historical_positions(
     Query(pagination): Query<PaginationParams>,
 ) -> Result<Json<PaginatedResponse<HistoricalPosition>>, StatusCode> {
     // println!("[API] Received request for GET /positions/history");
-    let owner_pub_key = check_auth(&headers).await?;
+    let owner_pub_key = check_auth_with_replay_protection(&db, &headers).await?;
     let page_size = pagination.page_size.unwrap_or(20);
+    let cursor = pagination.history_cursor()?;
     println!("[API] Attempting to get historical positions for public key: {:?} with page size: {} and cursor: {:?}", hex::encode(owner_pub_key), page_size, pagination.cursor);
     let positions = db
-        .get_historical_positions(&owner_pub_key, pagination.cursor, page_size)
+        .get_historical_positions(&owner_pub_key, cursor.as_ref(), page_size)
         .map_err(|e| {
             println!(
                 "[API] Error getting historical positions from database: {}",
@@ -117,6 +210,93 @@ async fn get_private_historical_positions(
     Ok(Json(positions))
 }
 
+const WS_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+const WS_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const WS_AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+// Close code for a failed or missing authentication frame (policy violation).
+const WS_CLOSE_UNAUTHORIZED: u16 = 1008;
+
+// First frame a stream client must send. Browsers can't set headers on a
+// websocket upgrade, so it carries what the private REST routes read from the
+// `x-signature`, `x-message` and `x-public-key` headers.
+#[derive(Deserialize)]
+struct WsAuthFrame {
+    signature: String,
+    message: String,
+    public_key: String,
+}
+
+fn check_ws_auth_frame(db: &Database, frame: &str) -> Result<[u8; 32], StatusCode> {
+    let frame: WsAuthFrame = serde_json::from_str(frame).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let pub_key = verify_signature(&frame.signature, &frame.message, &frame.public_key)?;
+    consume_auth_envelope(db, &pub_key, &frame.message)?;
+    Ok(pub_key)
+}
+
+// GET /positions/stream (websocket)
+async fn stream_position_updates(State(db): AppState, ws: WebSocketUpgrade) -> Response {
+    ws.max_message_size(WS_MAX_MESSAGE_SIZE)
+        .on_upgrade(move |socket| push_position_updates(socket, db))
+}
+
+async fn push_position_updates(mut socket: WebSocket, db: Arc<Database>) {
+    let auth = tokio::time::timeout(WS_AUTH_TIMEOUT, socket.recv()).await;
+    let owner_pub_key = match auth {
+        Ok(Some(Ok(Message::Text(frame)))) => check_ws_auth_frame(&db, &frame).ok(),
+        _ => None,
+    };
+    let Some(owner_pub_key) = owner_pub_key else {
+        let _ = socket
+            .send(Message::Close(Some(CloseFrame {
+                code: WS_CLOSE_UNAUTHORIZED,
+                reason: "unauthorized".into(),
+            })))
+            .await;
+        return;
+    };
+    let mut updates = db.subscribe_position_updates();
+    // Tells the client its subscription is live.
+    if socket
+        .send(Message::Text(r#"{"status":"subscribed"}"#.into()))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let owner = format!("0x{}", hex::encode(owner_pub_key));
+    let mut heartbeat = tokio::time::interval(WS_HEARTBEAT_INTERVAL);
+    heartbeat.tick().await;
+
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) if update.owner == owner => {
+                    let Ok(payload) = serde_json::to_string(&update) else { continue };
+                    if socket.send(Message::Text(payload.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    println!("[API] Position stream for {} lagged, skipped {} updates", owner, skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = heartbeat.tick() => {
+                if socket.send(Message::Ping(Vec::new().into())).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
 // GET /notes/unspent
 async fn get_unspent_notes(
     State(db): AppState,
@@ -127,10 +307,7 @@ async fn get_unspent_notes(
     let receiver_hash_header = headers
         .get("x-receiver-hash")
         .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| {
-            // println!("[API] Error: x-receiver-hash header missing or invalid");
-            StatusCode::BAD_REQUEST
-        })?;
+        .ok_or(StatusCode::BAD_REQUEST)?;
     // println!("[API] Found x-receiver-hash header {}" , receiver_hash_header);
 
     let receiver_hash = hex::decode(
@@ -161,7 +338,7 @@ async fn set_metadata(
         // println!("[API] Error: Payload size ({}) exceeds 4096 bytes", body.len());
         return Err(StatusCode::PAYLOAD_TOO_LARGE);
     }
-    let owner_pub_key = check_auth(&headers).await?;
+    let owner_pub_key = check_auth_with_replay_protection(&db, &headers).await?;
     // println!("[API] Attempting to set metadata for public key: {:?}", hex::encode(owner_pub_key));
     db.user_metadata
         .insert(owner_pub_key, body.to_vec())
@@ -176,15 +353,15 @@ async fn set_metadata(
 // GET /metadata
 async fn get_metadata(State(db): AppState, headers: HeaderMap) -> Result<Json<Value>, StatusCode> {
     // println!("[API] Received request for GET /metadata");
-    let owner_pub_key = check_auth(&headers).await?;
+    let owner_pub_key = check_auth_with_replay_protection(&db, &headers).await?;
     // println!("[API] Attempting to get metadata for public key: {:?}", hex::encode(owner_pub_key));
-    let metadata = db.user_metadata.get(&owner_pub_key).map_err(|e| {
+    let metadata = db.user_metadata.get(owner_pub_key).map_err(|e| {
         println!("[API] Error getting metadata from database: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
     // println!("[API] Successfully retrieved metadata");
     Ok(Json(
-        serde_json::json!({ "encrypted_metadata": metadata.map(|m| hex::encode(m)) }),
+        serde_json::json!({ "encrypted_metadata": metadata.map(hex::encode) }),
     ))
 }
 
@@ -230,18 +407,78 @@ async fn get_historical_positions_for_address(
     }
 
     let page_size = pagination.page_size.unwrap_or(20);
+    let cursor = pagination.history_cursor()?;
     let positions = db
-        .get_historical_positions(&owner_id, pagination.cursor, page_size)
+        .get_historical_positions(&owner_id, cursor.as_ref(), page_size)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(Json(positions))
 }
 
+// GET /sync-status
+async fn sync_status(State(db): AppState) -> Result<Json<SyncStatus>, StatusCode> {
+    db.sync_status()
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
 // health route
+// Liveness only: answers as long as the process is serving requests.
 async fn health() -> Result<Json<Value>, StatusCode> {
     Ok(Json(serde_json::json!({ "status": "ok" })))
 }
 
-pub async fn run_api_server(config: Arc<Config>, db: Arc<Database>) -> Result<()> {
+const READY_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// What `/ready` probes: the database, and the node through the indexer's own
+// client, so the probe shares its connection pool and timeouts.
+#[derive(Clone)]
+struct ReadyState {
+    db: Arc<Database>,
+    http_client: Arc<Client>,
+    config: Arc<Config>,
+}
+
+// Checks that sled answers a cheap read and the node answers a ledger-info
+// request, returning the reason for the first failure. A replaying indexer
+// never talks to the node, so the node isn't probed then.
+async fn check_readiness(state: &ReadyState) -> Result<(), String> {
+    state
+        .db
+        .get_last_processed_version()
+        .map_err(|e| format!("database read failed: {}", e))?;
+    if state.config.replay_path.is_some() {
+        return Ok(());
+    }
+    let response = state
+        .http_client
+        .get(format!("{}/", state.config.rpc_url))
+        .timeout(READY_PROBE_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| format!("node unreachable: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("node returned {}", response.status()));
+    }
+    Ok(())
+}
+
+// GET /ready
+async fn ready(State(state): State<ReadyState>) -> (StatusCode, Json<Value>) {
+    match check_readiness(&state).await {
+        Ok(()) => (StatusCode::OK, Json(serde_json::json!({ "status": "ready" }))),
+        Err(reason) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "status": "unavailable", "reason": reason })),
+        ),
+    }
+}
+
+pub async fn run_api_server(
+    config: Arc<Config>,
+    db: Arc<Database>,
+    http_client: Arc<Client>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
     // println!("[API Server] Initializing API server...");
     let cors = CorsLayer::new()
         .allow_origin(Any)
@@ -249,6 +486,8 @@ pub async fn run_api_server(config: Arc<Config>, db: Arc<Database>) -> Result<()
         .allow_headers(Any);
     let app = Router::new()
         .route("/positions/{position_id}", get(get_position_by_id))
+        .route("/positions/{position_id}/events", get(get_position_events))
+        .route("/notes/{note_id}", get(get_note_by_id))
         .route(
             "/positions/open/{address}",
             get(get_open_positions_for_address),
@@ -262,15 +501,276 @@ pub async fn run_api_server(config: Arc<Config>, db: Arc<Database>) -> Result<()
             "/private/positions/history",
             get(get_private_historical_positions),
         )
+        .route("/private/positions/stream", get(stream_position_updates))
         .route("/private/notes/unspent", get(get_unspent_notes))
         .route("/private/metadata", get(get_metadata).post(set_metadata))
         .route("/health", get(health))
+        .route("/sync-status", get(sync_status))
         .with_state(Arc::clone(&db))
+        .merge(Router::new().route("/ready", get(ready)).with_state(ReadyState {
+            db: Arc::clone(&db),
+            http_client,
+            config: Arc::clone(&config),
+        }))
         .layer(cors);
 
     // println!("[API Server] Binding to address: {}", &config.server_bind_address);
     let listener = tokio::net::TcpListener::bind(&config.server_bind_address).await?;
     // println!("[API Server] Listening on http://{}", &config.server_bind_address);
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+        })
+        .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const MESSAGE: &str = "cresca:auth:test";
+
+    fn signed_headers(signing_key: &SigningKey, message: &str) -> HeaderMap {
+        let signature = signing_key.sign(message.as_bytes());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-signature", hex::encode(signature.to_bytes()).parse().unwrap());
+        headers.insert("x-message", message.parse().unwrap());
+        headers.insert(
+            "x-public-key",
+            format!("0x{}", hex::encode(signing_key.verifying_key().to_bytes()))
+                .parse()
+                .unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_check_auth_accepts_valid_signature() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let headers = signed_headers(&signing_key, MESSAGE);
+        assert_eq!(
+            check_auth(&headers).await,
+            Ok(signing_key.verifying_key().to_bytes())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_auth_rejects_tampered_message() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let mut headers = signed_headers(&signing_key, MESSAGE);
+        headers.insert("x-message", "cresca:auth:other".parse().unwrap());
+        assert_eq!(check_auth(&headers).await, Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn test_check_auth_rejects_foreign_public_key() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let other_key = SigningKey::from_bytes(&[9u8; 32]);
+        let mut headers = signed_headers(&signing_key, MESSAGE);
+        headers.insert(
+            "x-public-key",
+            hex::encode(other_key.verifying_key().to_bytes()).parse().unwrap(),
+        );
+        assert_eq!(check_auth(&headers).await, Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn test_check_auth_requires_public_key_header() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let mut headers = signed_headers(&signing_key, MESSAGE);
+        headers.remove("x-public-key");
+        assert_eq!(check_auth(&headers).await, Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn test_replay_protection_rejects_reused_nonce() {
+        let db = Database::temporary().unwrap();
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let message = format!(r#"{{"nonce":1,"timestamp":"{}"}}"#, Utc::now().to_rfc3339());
+        let headers = signed_headers(&signing_key, &message);
+
+        assert!(check_auth_with_replay_protection(&db, &headers).await.is_ok());
+        assert_eq!(
+            check_auth_with_replay_protection(&db, &headers).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    // Serves only the position stream route, returning its websocket URL.
+    async fn stream_server(db: Arc<Database>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/private/positions/stream", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/private/positions/stream", get(stream_position_updates))
+            .with_state(db);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    fn ws_auth_frame(signing_key: &SigningKey, nonce: u64) -> String {
+        let message = format!(r#"{{"nonce":{},"timestamp":"{}"}}"#, nonce, Utc::now().to_rfc3339());
+        serde_json::json!({
+            "signature": hex::encode(signing_key.sign(message.as_bytes()).to_bytes()),
+            "message": message,
+            "public_key": hex::encode(signing_key.verifying_key().to_bytes()),
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_position_stream_pushes_owner_updates_after_auth_frame() {
+        use crate::database::DbWrite;
+        use crate::models::{Position, PositionEvent, PositionEventKind};
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let db = Arc::new(Database::temporary().unwrap());
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let (mut socket, _) = tokio_tungstenite::connect_async(stream_server(Arc::clone(&db)).await)
+            .await
+            .unwrap();
+        socket
+            .send(WsMessage::text(ws_auth_frame(&signing_key, 1)))
+            .await
+            .unwrap();
+
+        let ack = socket.next().await.unwrap().unwrap();
+        assert_eq!(ack, WsMessage::text(r#"{"status":"subscribed"}"#));
+
+        let position = Position {
+            position_id: "0x01".to_string(),
+            is_long: true,
+            entry_price: "100".to_string(),
+            margin: "10".to_string(),
+            size: "1".to_string(),
+            mark_price: None,
+            unrealized_pnl: None,
+        };
+        let opened = PositionEvent {
+            kind: PositionEventKind::Opened,
+            version: 1,
+            index: 0,
+            data: serde_json::json!({}),
+        };
+        db.apply_batch(vec![
+            DbWrite::AddPosition {
+                owner_pub_key: signing_key.verifying_key().to_bytes().to_vec(),
+                position,
+            },
+            DbWrite::AppendPositionEvent {
+                position_id: vec![0x01],
+                event: opened,
+            },
+        ])
+        .unwrap();
+
+        let Some(Ok(WsMessage::Text(pushed))) = socket.next().await else {
+            panic!("no update pushed");
+        };
+        let update: Value = serde_json::from_str(&pushed).unwrap();
+        assert_eq!(
+            update["owner"],
+            format!("0x{}", hex::encode(signing_key.verifying_key().to_bytes()))
+        );
+        assert_eq!(update["kind"], "Opened");
+    }
+
+    #[tokio::test]
+    async fn test_position_stream_closes_on_bad_auth_frame() {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let db = Arc::new(Database::temporary().unwrap());
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let url = stream_server(Arc::clone(&db)).await;
+
+        let replayed = ws_auth_frame(&signing_key, 1);
+        db.consume_auth_nonce(&signing_key.verifying_key().to_bytes(), 1).unwrap();
+        for frame in [replayed, "not json".to_string()] {
+            let (mut socket, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            socket.send(WsMessage::text(frame)).await.unwrap();
+            match socket.next().await {
+                Some(Ok(WsMessage::Close(Some(close)))) => {
+                    assert_eq!(u16::from(close.code), WS_CLOSE_UNAUTHORIZED)
+                }
+                other => panic!("expected close frame, got {:?}", other),
+            }
+        }
+    }
+
+    async fn mock_node(status: StatusCode) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().route("/", get(move || async move { status }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        rpc_url
+    }
+
+    // Nothing listens on a freshly released port.
+    fn dead_node() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    fn ready_state(rpc_url: String, replay_path: Option<String>) -> ReadyState {
+        ReadyState {
+            db: Arc::new(Database::temporary().unwrap()),
+            http_client: Arc::new(Client::new()),
+            config: Arc::new(Config {
+                replay_path,
+                ..Config::for_tests(rpc_url)
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ready_when_db_and_node_respond() {
+        let state = ready_state(mock_node(StatusCode::OK).await, None);
+        let (status, body) = ready(State(state)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_when_node_is_degraded() {
+        let state = ready_state(mock_node(StatusCode::BAD_GATEWAY).await, None);
+        let (status, body) = ready(State(state)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["reason"], "node returned 502 Bad Gateway");
+
+        let (status, body) = ready(State(ready_state(dead_node(), None))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["reason"].as_str().unwrap().starts_with("node unreachable"));
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_when_database_read_fails() {
+        let state = ready_state(mock_node(StatusCode::OK).await, None);
+        state
+            .db
+            .indexer_state
+            .insert(b"last_processed_version", &[0u8; 3])
+            .unwrap();
+        let (status, body) = ready(State(state)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["reason"].as_str().unwrap().starts_with("database read failed"));
+    }
+
+    #[tokio::test]
+    async fn test_ready_skips_node_probe_when_replaying() {
+        let state = ready_state(dead_node(), Some("capture.jsonl".to_string()));
+        let (status, _) = ready(State(state)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_parse_auth_envelope_rejects_stale_timestamp() {
+        let now = Utc::now();
+        let stale = now - chrono::Duration::seconds(AUTH_MESSAGE_MAX_AGE_SECONDS + 1);
+        let message = format!(r#"{{"nonce":1,"timestamp":"{}"}}"#, stale.to_rfc3339());
+        assert!(parse_auth_envelope(&message, now).is_err());
+        assert!(parse_auth_envelope(&message, stale).is_ok());
+    }
+}
@@ -1,11 +1,81 @@
+use anyhow::anyhow;
 use std::env;
 
+// Where a cold start begins, from `INDEXER_START_VERSION`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StartVersion {
+    At(u64),
+    // "FULL": backfill from the version the NOX modules were published at.
+    // Nothing older can hold NOX events, so it is not clamped.
+    Full(u64),
+}
+
+// Resolves `INDEXER_START_VERSION`: a version number, or FULL for the version
+// the NOX modules were published at, read from `INDEXER_MODULE_GENESIS_VERSION`.
+fn parse_start_version(value: &str) -> Result<StartVersion, anyhow::Error> {
+    let value = value.trim();
+    if !value.eq_ignore_ascii_case("full") {
+        return value.parse().map(StartVersion::At).map_err(|_| {
+            anyhow!("INDEXER_START_VERSION must be a version number or FULL, got {:?}", value)
+        });
+    }
+    let genesis = env::var("INDEXER_MODULE_GENESIS_VERSION").map_err(|_| {
+        anyhow!("INDEXER_START_VERSION=FULL requires INDEXER_MODULE_GENESIS_VERSION, the version the NOX modules were published at")
+    })?;
+    genesis.trim().parse().map(StartVersion::Full).map_err(|_| {
+        anyhow!("INDEXER_MODULE_GENESIS_VERSION must be a version number, got {:?}", genesis)
+    })
+}
+
+// Canonical form for comparing Aptos addresses: `0x` plus 64 lowercase hex
+// digits, so `0x2` and its zero-padded long form compare equal.
+pub fn normalize_address(address: &str) -> String {
+    let hex = address.trim();
+    let hex = hex.strip_prefix("0x").or_else(|| hex.strip_prefix("0X")).unwrap_or(hex);
+    format!("0x{:0>64}", hex.to_ascii_lowercase())
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub rpc_url: String,
-    pub nox_module_address: String, // Changed from privacy_proxy_address
+    // Normalized addresses of every NOX module (token pool, privacy proxy,
+    // clearing house), from a comma-separated `NOX_MODULE_ADDRESS`.
+    pub nox_module_addresses: Vec<String>,
     pub db_path: String,
+    // sled fsyncs its write-ahead log in the background at this interval.
+    pub db_flush_every_ms: u64,
     pub server_bind_address: String,
+    // When set, every transaction fetched from the node is appended here as JSONL.
+    pub capture_path: Option<String>,
+    // When set, transactions are read from this capture file instead of the node.
+    pub replay_path: Option<String>,
+    // Buffered writes are flushed once this many events are pending...
+    pub write_batch_events: usize,
+    // ...or once this long has passed since the previous flush.
+    pub write_flush_interval_ms: u64,
+    // Node HTTP client tuning. Like reqwest, idle connections are kept without
+    // a cap by default; the indexer polls serially, so it only ever needs one.
+    // Against a local node, a serial poll on the shared client averages ~0.4ms
+    // versus ~85ms with a new client per request, mostly client setup and a
+    // new connection (`bench_serial_poll_latency_shared_vs_new_client`). A
+    // remote HTTPS node adds a TLS handshake to every unpooled request on top.
+    pub http_pool_max_idle_per_host: usize,
+    // TCP keep-alive probe interval; 0 disables it.
+    pub http_tcp_keepalive_secs: u64,
+    // Upper bound on a whole node request, so a stalled node can't hang a
+    // poll; 0 disables it.
+    pub http_timeout_secs: u64,
+    // Upper bound on opening a connection to the node; 0 disables it.
+    pub http_connect_timeout_secs: u64,
+    // Speak HTTP/2 without negotiation. Only for nodes known to support it.
+    pub http2_prior_knowledge: bool,
+    // First version to index on a cold start; defaults to 100 below the tip.
+    // FULL resolves to the modules' deployment version.
+    pub start_version: Option<StartVersion>,
+    // A numeric start further than this behind the tip is clamped; FULL is not.
+    pub max_cold_start_lag: u64,
+    // `--reindex`: ignore the persisted cursor and cold-start again.
+    pub reindex: bool,
 }
 
 impl Config {
@@ -14,11 +84,81 @@ impl Config {
         Ok(Self {
             rpc_url: env::var("APTOS_RPC_URL")
                 .unwrap_or_else(|_| "https://api.testnet.aptoslabs.com/v1".to_string()),
-            nox_module_address: env::var("NOX_MODULE_ADDRESS")
-                .unwrap_or_else(|_| "0x0000000000000000000000000000000000000000000000000000000000000002".to_string()),
+            nox_module_addresses: env::var("NOX_MODULE_ADDRESS")
+                .unwrap_or_else(|_| "0x0000000000000000000000000000000000000000000000000000000000000002".to_string())
+                .split(',')
+                .filter(|address| !address.trim().is_empty())
+                .map(normalize_address)
+                .collect(),
             db_path: env::var("DB_PATH").unwrap_or_else(|_| "./db".to_string()),
+            db_flush_every_ms: env::var("DB_FLUSH_EVERY_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(500),
             server_bind_address: env::var("SERVER_BIND_ADDRESS")
                 .unwrap_or_else(|_| "0.0.0.0:3000".to_string()),
+            capture_path: env::var("INDEXER_CAPTURE_PATH").ok(),
+            replay_path: env::var("INDEXER_REPLAY_PATH").ok(),
+            write_batch_events: env::var("INDEXER_WRITE_BATCH_EVENTS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100),
+            write_flush_interval_ms: env::var("INDEXER_WRITE_FLUSH_INTERVAL_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1000),
+            http_pool_max_idle_per_host: env::var("HTTP_POOL_MAX_IDLE_PER_HOST")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(usize::MAX),
+            http_tcp_keepalive_secs: env::var("HTTP_TCP_KEEPALIVE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30),
+            http_timeout_secs: env::var("HTTP_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30),
+            http_connect_timeout_secs: env::var("HTTP_CONNECT_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10),
+            http2_prior_knowledge: env::var("HTTP2_PRIOR_KNOWLEDGE")
+                .is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true")),
+            // A typo here must not silently fall back to tailing from the tip.
+            start_version: match env::var("INDEXER_START_VERSION") {
+                Ok(v) if !v.trim().is_empty() => Some(parse_start_version(&v)?),
+                _ => None,
+            },
+            max_cold_start_lag: env::var("INDEXER_MAX_COLD_START_LAG")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1_000_000),
+            reindex: env::args().skip(1).any(|arg| arg == "--reindex"),
         })
     }
+
+    // Fixed settings for tests, independent of `.env` and the process environment.
+    #[cfg(test)]
+    pub fn for_tests(rpc_url: String) -> Self {
+        Self {
+            rpc_url,
+            nox_module_addresses: vec![normalize_address("0x2")],
+            db_path: "./db".to_string(),
+            db_flush_every_ms: 500,
+            server_bind_address: "127.0.0.1:0".to_string(),
+            capture_path: None,
+            replay_path: None,
+            write_batch_events: 100,
+            write_flush_interval_ms: 1000,
+            http_pool_max_idle_per_host: usize::MAX,
+            http_tcp_keepalive_secs: 30,
+            http_timeout_secs: 30,
+            http_connect_timeout_secs: 10,
+            http2_prior_knowledge: false,
+            start_version: None,
+            max_cold_start_lag: 1_000_000,
+            reindex: false,
+        }
+    }
 }
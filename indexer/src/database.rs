@@ -1,8 +1,20 @@
-use anyhow::Result;
-use sled::{Db, Tree};
-use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionError, Transactional},
+    Batch, Db, Tree,
+};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
 
-use crate::models::{HistoricalPosition, PaginatedResponse, Position, PositionStatus, UnspentNote};
+use crate::models::{
+    HistoricalPosition, HistoryCursor, LedgerTip, PaginatedResponse, Position, PositionEvent, PositionStatus,
+    PositionUpdate, SyncStatus, UnspentNote,
+};
 
 #[derive(Clone)]
 pub struct Database {
@@ -19,8 +31,26 @@ pub struct Database {
     // K: position_id (bytes), V: owner_pub_key (bytes)
     pub position_id_to_owner: Tree,
     pub positions_by_id: Tree,
+    // K: position_event_key (position_id, version, event index), V: PositionEvent (json)
+    pub position_events: Tree,
+    // K: note_id ("0x..." string bytes), V: empty; notes that have been claimed
+    pub spent_notes: Tree,
+    // K: note_id ("0x..." string bytes), V: receiver_hash (bytes); unspent notes only
+    pub note_receivers: Tree,
+    // K: owner_pub_key (bytes), V: last accepted auth nonce (u64, big-endian)
+    pub auth_nonces: Tree,
+    // K: state key (e.g. "last_processed_version"), V: u64 (big-endian)
+    pub indexer_state: Tree,
+    // Live feed of newly indexed position events, consumed by the websocket API.
+    position_updates: broadcast::Sender<PositionUpdate>,
+    // The latest and previous ledger tips seen by the indexer; not persisted.
+    ledger_tips: Arc<Mutex<(Option<LedgerTip>, Option<LedgerTip>)>>,
 }
 
+const POSITION_UPDATES_CAPACITY: usize = 1024;
+
+const LAST_PROCESSED_VERSION_KEY: &[u8] = b"last_processed_version";
+
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 #[serde(tag = "status", content = "data")] 
 pub enum PositionData {
@@ -29,101 +59,166 @@ pub enum PositionData {
 }
 
 impl Database {
-    pub fn new(path: &str) -> Result<Self> {
-        let _db = Arc::new(sled::open(path)?);
-        Ok(Self {
+    pub fn new(path: &str, flush_every_ms: u64) -> Result<Self> {
+        Self::from_db(
+            sled::Config::new()
+                .path(path)
+                .flush_every_ms(Some(flush_every_ms))
+                .open()?,
+        )
+    }
+
+    #[cfg(test)]
+    pub fn temporary() -> Result<Self> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: Db) -> Result<Self> {
+        let _db = Arc::new(db);
+        let database = Self {
             open_positions: _db.open_tree("open_positions")?,
             historical_positions: _db.open_tree("historical_positions")?,
             unspent_notes: _db.open_tree("unspent_notes")?,
             user_metadata: _db.open_tree("user_metadata")?,
             position_id_to_owner: _db.open_tree("pos_id_to_owner")?,
             positions_by_id: _db.open_tree("positions_by_id")?, 
+            position_events: _db.open_tree("position_events")?,
+            spent_notes: _db.open_tree("spent_notes")?,
+            note_receivers: _db.open_tree("note_receivers")?,
+            auth_nonces: _db.open_tree("auth_nonces")?,
+            indexer_state: _db.open_tree("indexer_state")?,
+            position_updates: broadcast::channel(POSITION_UPDATES_CAPACITY).0,
+            ledger_tips: Arc::default(),
             _db,
-        })
+        };
+        database.backfill_note_receivers()?;
+        Ok(database)
     }
 
-    pub fn add_open_position(&self, owner_pub_key: &[u8], position: Position) -> Result<()> {
-        let mut positions = self.get_open_positions(owner_pub_key)?;
-        if !positions
-            .iter()
-            .any(|p| p.position_id == position.position_id)
-        {
-            positions.push(position.clone());
+    // Builds the note_id index for databases written before it existed.
+    fn backfill_note_receivers(&self) -> Result<()> {
+        if !self.note_receivers.is_empty() {
+            return Ok(());
         }
-        self.open_positions
-            .insert(owner_pub_key, serde_json::to_vec(&positions)?)?;
-        self.position_id_to_owner
-            .insert(position.position_id.clone(), owner_pub_key)?;
-        let data = PositionData::Open(position.clone());
-        self.positions_by_id.insert(position.position_id.as_bytes(), serde_json::to_vec(&data)?)?;
+        for item in self.unspent_notes.iter() {
+            let (receiver_hash, value) = item?;
+            let notes: Vec<UnspentNote> = serde_json::from_slice(&value)?;
+            for note in notes {
+                self.note_receivers
+                    .insert(note.note_id.as_bytes(), receiver_hash.as_ref())?;
+            }
+        }
+        Ok(())
+    }
 
-        println!("positions_by_id insert {}" , position.position_id);
-        // println!("Inserted position Id for {:#?} owner {:#?}" , position.position_id, hex::encode(owner_pub_key));
+    // Blocks until all pending writes are durable on disk.
+    pub fn flush(&self) -> Result<()> {
+        self._db.flush()?;
         Ok(())
     }
 
-    pub fn move_to_historical(
-        &self,
-        position_id: &[u8],
-        status: PositionStatus,
-        final_pnl: String,
-        owner_address: String, 
-    ) -> Result<()> {
-        // println!("Moving to historical records {:#?}" , format!("0x{}" , hex::encode(position_id)));
-        let owner_pub_key = match self
-            .position_id_to_owner
-            .get(format!("0x{}", hex::encode(position_id)))?
-        {
-            Some(pk) => pk,
-            None => return Ok(()), // Position owner not found, maybe already processed
-        };
+    // Same as `flush`, but yields to the runtime while the fsync runs.
+    pub async fn flush_async(&self) -> Result<()> {
+        self._db.flush_async().await?;
+        Ok(())
+    }
 
-        // println!("Owner of position {:#?}" , hex::encode(&owner_pub_key));
+    // --- Indexer Cursor ---
 
-        let mut open_positions = self.get_open_positions(&owner_pub_key)?;
+    // A cursor that isn't 8 bytes is an error rather than "never indexed", so a
+    // corrupt value can't silently send the indexer back to a cold start.
+    pub fn get_last_processed_version(&self) -> Result<Option<u64>> {
+        match self.indexer_state.get(LAST_PROCESSED_VERSION_KEY)? {
+            Some(bytes) => {
+                let bytes = bytes
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| anyhow!("corrupt last processed version"))?;
+                Ok(Some(u64::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
 
-        if let Some(index) = open_positions
-            .iter()
-            .position(|p| p.position_id.replace("0x", "") == hex::encode(position_id))
-        {
-            let position_to_move = open_positions.remove(index);
-            // println!("Position found {}" , index);
-            self.open_positions
-                .insert(&owner_pub_key, serde_json::to_vec(&open_positions)?)?;
+    // --- Sync Status ---
 
-            let historical_pos = HistoricalPosition {
-                position: position_to_move,
-                status,
-                final_pnl,
-                owner_address
-            };
+    pub fn record_ledger_tip(&self, tip: LedgerTip) {
+        let mut tips = self.ledger_tips.lock().unwrap_or_else(|e| e.into_inner());
+        match tips.0 {
+            Some(latest) if latest.version >= tip.version => {}
+            latest => *tips = (Some(tip), latest),
+        }
+    }
 
-            let mut historical_positions =
-                self.get_historical_positions_internal(&owner_pub_key)?;
-            historical_positions.insert(0, historical_pos.clone()); // Insert at the beginning for chronological order
-            self.historical_positions
-                .insert(&owner_pub_key, serde_json::to_vec(&historical_positions)?)?;
+    pub fn sync_status(&self) -> Result<SyncStatus> {
+        let last_processed_version = self.get_last_processed_version()?;
+        let (latest, previous) = *self.ledger_tips.lock().unwrap_or_else(|e| e.into_inner());
+        // Unknown until the first flush; measuring from version 0 would report
+        // the whole ledger height as lag after every cold start.
+        let lag_versions = latest
+            .zip(last_processed_version)
+            .map(|(tip, last)| tip.version.saturating_sub(last));
 
-            self.position_id_to_owner
-                .remove(format!("0x{}", hex::encode(position_id)))?;
-            let data = PositionData::Historical(historical_pos);
-            self.positions_by_id.insert(format!("0x{}", hex::encode(position_id)).as_bytes(), serde_json::to_vec(&data)?)?;
+        let versions_per_second = match (latest, previous) {
+            (Some(latest), Some(previous)) => match (latest.timestamp_usecs, previous.timestamp_usecs) {
+                (Some(now), Some(then)) if now > then => {
+                    Some((latest.version - previous.version) as f64 * 1e6 / (now - then) as f64)
+                }
+                _ => None,
+            },
+            _ => None,
+        };
 
-            // self.position_id_to_owner.remove()
-            // println!("Removed position {:#?}" , position_id);
-        }
+        Ok(SyncStatus {
+            last_processed_version,
+            latest_ledger_version: latest.map(|tip| tip.version),
+            lag_versions,
+            estimated_lag_seconds: lag_versions
+                .zip(versions_per_second)
+                .map(|(lag, rate)| lag as f64 / rate),
+        })
+    }
 
+    // Applies `writes` in order as one atomic commit across all trees, so a
+    // chunk either lands in full or not at all. Position events recorded by the
+    // batch are published to live subscribers once it has committed.
+    pub fn apply_batch(&self, writes: Vec<DbWrite>) -> Result<()> {
+        let mut staged = StagedWrites::new(self);
+        for write in writes {
+            staged.stage(write)?;
+        }
+        for update in staged.commit()? {
+            // No subscribers is not an error.
+            let _ = self.position_updates.send(update);
+        }
         Ok(())
     }
 
-    pub fn get_position_by_id(&self, position_id: &[u8]) -> Result<Option<PositionData>> {
-        // println!("get position_id {}", hex::encode(position_id));
-        match self.positions_by_id.get(format!("0x{}", hex::encode(position_id)).as_bytes())? {
+    fn get_position_data(&self, key: &[u8]) -> Result<Option<PositionData>> {
+        match self.positions_by_id.get(key)? {
             Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
             None => Ok(None),
         }
     }
 
+    pub fn get_position_by_id(&self, position_id: &[u8]) -> Result<Option<PositionData>> {
+        // println!("get position_id {}", hex::encode(position_id));
+        self.get_position_data(format!("0x{}", hex::encode(position_id)).as_bytes())
+    }
+
+    pub fn subscribe_position_updates(&self) -> broadcast::Receiver<PositionUpdate> {
+        self.position_updates.subscribe()
+    }
+
+    // Oldest first, since keys sort by version and then event index.
+    pub fn get_position_events(&self, position_id: &[u8]) -> Result<Vec<PositionEvent>> {
+        self.position_events
+            .scan_prefix(position_events_prefix(position_id))
+            .values()
+            .map(|data| Ok(serde_json::from_slice(&data?)?))
+            .collect()
+    }
+
     pub fn get_open_positions(&self, owner_pub_key: &[u8]) -> Result<Vec<Position>> {
         match self.open_positions.get(owner_pub_key)? {
             Some(data) => Ok(serde_json::from_slice(&data)?),
@@ -146,11 +241,19 @@ impl Database {
     pub fn get_historical_positions(
         &self,
         owner_pub_key: &[u8],
-        cursor: Option<usize>,
+        cursor: Option<&HistoryCursor>,
         page_size: usize,
     ) -> Result<PaginatedResponse<HistoricalPosition>> {
         let all_positions = self.get_historical_positions_internal(owner_pub_key)?;
-        let start = cursor.unwrap_or(0);
+        let total = Some(all_positions.len());
+        // Resume right after the cursor's row; an unknown row yields an empty page.
+        let start = match cursor {
+            Some(cursor) => all_positions
+                .iter()
+                .position(|p| p.position.position_id == cursor.last_position_id)
+                .map_or(all_positions.len(), |index| index + 1),
+            None => 0,
+        };
         let end = std::cmp::min(start + page_size, all_positions.len());
 
         if start >= all_positions.len() {
@@ -158,13 +261,19 @@ impl Database {
                 items: vec![],
                 has_more: false,
                 next_cursor: None,
+                total,
             });
         }
 
         let items = all_positions[start..end].to_vec();
         let has_more = end < all_positions.len();
         let next_cursor = if has_more {
-            Some(end.to_string())
+            items.last().map(|last| {
+                HistoryCursor {
+                    last_position_id: last.position.position_id.clone(),
+                }
+                .encode()
+            })
         } else {
             None
         };
@@ -173,63 +282,621 @@ impl Database {
             items,
             has_more,
             next_cursor,
+            total,
         })
     }
 
     // --- Note Management ---
 
-    pub fn add_unspent_note(&self, note: &UnspentNote) -> Result<()> {
+    pub fn get_unspent_notes(&self, receiver_hash: &[u8]) -> Result<Vec<UnspentNote>> {
+        match self.unspent_notes.get(receiver_hash)? {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn get_unspent_note(&self, note_id: &[u8]) -> Result<Option<UnspentNote>> {
+        let note_id = format!("0x{}", hex::encode(note_id));
+        let Some(receiver_hash) = self.note_receivers.get(note_id.as_bytes())? else {
+            return Ok(None);
+        };
+        Ok(self
+            .get_unspent_notes(&receiver_hash)?
+            .into_iter()
+            .find(|n| n.note_id == note_id))
+    }
+
+    // --- Auth Replay Protection ---
+
+    /// Records `nonce` as the latest one seen for `owner_pub_key` if it is
+    /// strictly greater than the stored value. Returns `false` for a stale or
+    /// reused nonce, leaving the stored value untouched.
+    pub fn consume_auth_nonce(&self, owner_pub_key: &[u8], nonce: u64) -> Result<bool> {
+        let decode = |bytes: &[u8]| -> u64 {
+            bytes.try_into().map(u64::from_be_bytes).unwrap_or(0)
+        };
+        let previous = self.auth_nonces.fetch_and_update(owner_pub_key, |old| match old {
+            Some(last) if decode(last) >= nonce => Some(last.to_vec()),
+            _ => Some(nonce.to_be_bytes().to_vec()),
+        })?;
+        Ok(previous.is_none_or(|last| decode(&last) < nonce))
+    }
+
+    // pub fn set_user_metadata(&self, owner_pub_key: &[u8], encrypted_blob: Vec<u8>) -> Result<()> {
+    //     self.user_metadata.insert(owner_pub_key, encrypted_blob)?;
+    //     Ok(())
+    // }
+
+    // pub fn get_user_metadata(&self, owner_pub_key: &[u8]) -> Result<Option<Vec<u8>>> {
+    //     Ok(self.user_metadata.get(owner_pub_key)?.map(|iv| iv.to_vec()))
+    // }
+}
+
+// --- Batched Writes ---
+
+// One indexer write. A chunk of these is applied atomically by `apply_batch`.
+pub enum DbWrite {
+    AddNote(UnspentNote),
+    RemoveNote {
+        note_id: Vec<u8>,
+    },
+    AddPosition {
+        owner_pub_key: Vec<u8>,
+        position: Position,
+    },
+    MoveToHistorical {
+        position_id: Vec<u8>,
+        status: PositionStatus,
+        final_pnl: String,
+        owner_address: String,
+    },
+    UpdateMark {
+        position_id: Vec<u8>,
+        mark_price: String,
+        unrealized_pnl: String,
+    },
+    // Must be staged before the position is moved to history, since the owner
+    // it is published to is resolved from the open position.
+    AppendPositionEvent {
+        position_id: Vec<u8>,
+        event: PositionEvent,
+    },
+    SetLastProcessedVersion(u64),
+}
+
+// "0x<position_id>/"; the separator keeps one id from prefixing a longer one.
+fn position_events_prefix(position_id: &[u8]) -> Vec<u8> {
+    format!("0x{}/", hex::encode(position_id)).into_bytes()
+}
+
+// The prefix followed by the big-endian version and event index, so a
+// position's events sort in the order they were emitted.
+fn position_event_key(position_id: &[u8], version: u64, index: u64) -> Vec<u8> {
+    let mut key = position_events_prefix(position_id);
+    key.extend_from_slice(&version.to_be_bytes());
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+// Writes staged against one tree. Reads see staged values first, so later
+// writes in a batch build on earlier ones.
+struct StagedTree<'a> {
+    tree: &'a Tree,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> StagedTree<'a> {
+    fn new(tree: &'a Tree) -> Self {
+        Self {
+            tree,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.writes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => Ok(self.tree.get(key)?.map(|value| value.to_vec())),
+        }
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, key: &[u8]) -> Result<Option<T>> {
+        match self.get(key)? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], value: Vec<u8>) {
+        self.writes.insert(key.to_vec(), Some(value));
+    }
+
+    fn insert_json<T: Serialize>(&mut self, key: &[u8], value: &T) -> Result<()> {
+        self.insert(key, serde_json::to_vec(value)?);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    fn into_batch(self) -> Option<(Tree, Batch)> {
+        if self.writes.is_empty() {
+            return None;
+        }
+        let mut batch = Batch::default();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.insert(key, value),
+                None => batch.remove(key),
+            }
+        }
+        Some((self.tree.clone(), batch))
+    }
+}
+
+// The staged state of every tree an indexer write can touch, plus the live
+// updates to publish once the batch commits.
+struct StagedWrites<'a> {
+    open_positions: StagedTree<'a>,
+    historical_positions: StagedTree<'a>,
+    unspent_notes: StagedTree<'a>,
+    spent_notes: StagedTree<'a>,
+    position_id_to_owner: StagedTree<'a>,
+    positions_by_id: StagedTree<'a>,
+    position_events: StagedTree<'a>,
+    note_receivers: StagedTree<'a>,
+    indexer_state: StagedTree<'a>,
+    updates: Vec<PositionUpdate>,
+}
+
+impl<'a> StagedWrites<'a> {
+    fn new(db: &'a Database) -> Self {
+        Self {
+            open_positions: StagedTree::new(&db.open_positions),
+            historical_positions: StagedTree::new(&db.historical_positions),
+            unspent_notes: StagedTree::new(&db.unspent_notes),
+            spent_notes: StagedTree::new(&db.spent_notes),
+            position_id_to_owner: StagedTree::new(&db.position_id_to_owner),
+            positions_by_id: StagedTree::new(&db.positions_by_id),
+            position_events: StagedTree::new(&db.position_events),
+            note_receivers: StagedTree::new(&db.note_receivers),
+            indexer_state: StagedTree::new(&db.indexer_state),
+            updates: Vec::new(),
+        }
+    }
+
+    fn stage(&mut self, write: DbWrite) -> Result<()> {
+        match write {
+            DbWrite::AddNote(note) => self.add_note(note),
+            DbWrite::RemoveNote { note_id } => self.remove_note(&note_id),
+            DbWrite::AddPosition {
+                owner_pub_key,
+                position,
+            } => self.add_position(&owner_pub_key, position),
+            DbWrite::MoveToHistorical {
+                position_id,
+                status,
+                final_pnl,
+                owner_address,
+            } => self.move_to_historical(&position_id, status, final_pnl, owner_address),
+            DbWrite::UpdateMark {
+                position_id,
+                mark_price,
+                unrealized_pnl,
+            } => self.update_mark(&position_id, mark_price, unrealized_pnl),
+            DbWrite::AppendPositionEvent { position_id, event } => {
+                self.append_position_event(&position_id, event)
+            }
+            DbWrite::SetLastProcessedVersion(version) => {
+                self.indexer_state
+                    .insert(LAST_PROCESSED_VERSION_KEY, version.to_be_bytes().to_vec());
+                Ok(())
+            }
+        }
+    }
+
+    // Idempotent: a note that is already unspent, or was already claimed, is
+    // left alone so reprocessing a version range can't duplicate or revive it.
+    fn add_note(&mut self, note: UnspentNote) -> Result<()> {
+        if self.spent_notes.get(note.note_id.as_bytes())?.is_some() {
+            println!("Note {} already spent, skipping", note.note_id);
+            return Ok(());
+        }
         let receiver_hash_bytes = hex::decode(
             note.note
                 .receiver_hash
                 .strip_prefix("0x")
                 .unwrap_or(&note.note.receiver_hash),
         )?;
-        let mut notes = self.get_unspent_notes(&receiver_hash_bytes)?;
-        notes.push(note.clone());
-        self.unspent_notes
-            .insert(receiver_hash_bytes, serde_json::to_vec(&notes)?)?;
-        println!("Note added {}", format!("{}", note.note_id));
-        Ok(())
+        let mut notes: Vec<UnspentNote> = self
+            .unspent_notes
+            .get_json(&receiver_hash_bytes)?
+            .unwrap_or_default();
+        if notes.iter().any(|n| n.note_id == note.note_id) {
+            return Ok(());
+        }
+        println!("Note added {}", note.note_id);
+        self.note_receivers
+            .insert(note.note_id.as_bytes(), receiver_hash_bytes.clone());
+        notes.push(note);
+        self.unspent_notes.insert_json(&receiver_hash_bytes, &notes)
     }
 
-    pub fn remove_unspent_note(&self, note_id_to_remove: &[u8]) -> Result<()> {
+    fn remove_note(&mut self, note_id_to_remove: &[u8]) -> Result<()> {
+        println!("Removing Note 0x{}", hex::encode(note_id_to_remove));
+        let note_id = format!("0x{}", hex::encode(note_id_to_remove));
+        self.spent_notes.insert(note_id.as_bytes(), Vec::new());
+        let Some(receiver_hash) = self.note_receivers.get(note_id.as_bytes())? else {
+            return Ok(());
+        };
+        self.note_receivers.remove(note_id.as_bytes());
+
+        let mut notes: Vec<UnspentNote> = self
+            .unspent_notes
+            .get_json(&receiver_hash)?
+            .unwrap_or_default();
+        notes.retain(|n| n.note_id != note_id);
+        self.unspent_notes.insert_json(&receiver_hash, &notes)?;
         println!(
-            "Removing Note {}",
-            format!("0x{}", hex::encode(note_id_to_remove))
+            "Note retained 0x{} now notes length {}",
+            hex::encode(note_id_to_remove),
+            notes.len()
         );
-        for item in self.unspent_notes.iter() {
-            let (key, value) = item?;
-            let mut notes: Vec<UnspentNote> = serde_json::from_slice(&value)?;
-            let original_len = notes.len();
-            notes.retain(|n| n.note_id != format!("0x{}", hex::encode(note_id_to_remove)));
-            if notes.len() < original_len {
-                self.unspent_notes
-                    .insert(key, serde_json::to_vec(&notes)?)?;
-                println!(
-                    "Note retained {} now notes length {}",
-                    format!("0x{}", hex::encode(note_id_to_remove)),
-                    notes.len()
-                );
-                return Ok(());
-            }
+        Ok(())
+    }
+
+    // Idempotent: re-opening a position that is already open or already closed
+    // (e.g. when a version range is reprocessed) leaves the stored state alone.
+    fn add_position(&mut self, owner_pub_key: &[u8], position: Position) -> Result<()> {
+        let key = position.position_id.as_bytes();
+        if let Some(PositionData::Historical(_)) = self.positions_by_id.get_json(key)? {
+            return Ok(());
+        }
+        let mut positions: Vec<Position> = self
+            .open_positions
+            .get_json(owner_pub_key)?
+            .unwrap_or_default();
+        if !positions
+            .iter()
+            .any(|p| p.position_id == position.position_id)
+        {
+            positions.push(position.clone());
         }
+        self.open_positions.insert_json(owner_pub_key, &positions)?;
+        self.position_id_to_owner.insert(key, owner_pub_key.to_vec());
+        self.positions_by_id
+            .insert_json(key, &PositionData::Open(position.clone()))?;
+
+        println!("positions_by_id insert {}" , position.position_id);
         Ok(())
     }
 
-    pub fn get_unspent_notes(&self, receiver_hash: &[u8]) -> Result<Vec<UnspentNote>> {
-        match self.unspent_notes.get(receiver_hash)? {
-            Some(data) => Ok(serde_json::from_slice(&data)?),
-            None => Ok(Vec::new()),
+    fn move_to_historical(
+        &mut self,
+        position_id: &[u8],
+        status: PositionStatus,
+        final_pnl: String,
+        owner_address: String,
+    ) -> Result<()> {
+        let key = format!("0x{}", hex::encode(position_id));
+        let owner_pub_key = match self.position_id_to_owner.get(key.as_bytes())? {
+            Some(pk) => pk,
+            None => return Ok(()), // Position owner not found, maybe already processed
+        };
+
+        let mut open_positions: Vec<Position> = self
+            .open_positions
+            .get_json(&owner_pub_key)?
+            .unwrap_or_default();
+
+        if let Some(index) = open_positions
+            .iter()
+            .position(|p| p.position_id.replace("0x", "") == hex::encode(position_id))
+        {
+            let position_to_move = open_positions.remove(index);
+            self.open_positions.insert_json(&owner_pub_key, &open_positions)?;
+
+            let historical_pos = HistoricalPosition {
+                position: position_to_move,
+                status,
+                final_pnl,
+                owner_address
+            };
+
+            let mut historical_positions: Vec<HistoricalPosition> = self
+                .historical_positions
+                .get_json(&owner_pub_key)?
+                .unwrap_or_default();
+            historical_positions.insert(0, historical_pos.clone()); // Insert at the beginning for chronological order
+            self.historical_positions
+                .insert_json(&owner_pub_key, &historical_positions)?;
+
+            self.position_id_to_owner.remove(key.as_bytes());
+            self.positions_by_id
+                .insert_json(key.as_bytes(), &PositionData::Historical(historical_pos))?;
         }
+
+        Ok(())
     }
 
-    // pub fn set_user_metadata(&self, owner_pub_key: &[u8], encrypted_blob: Vec<u8>) -> Result<()> {
-    //     self.user_metadata.insert(owner_pub_key, encrypted_blob)?;
-    //     Ok(())
-    // }
+    // Records the latest mark price and unrealized PnL on an open position.
+    // Positions that are unknown or already closed are left untouched.
+    fn update_mark(
+        &mut self,
+        position_id: &[u8],
+        mark_price: String,
+        unrealized_pnl: String,
+    ) -> Result<()> {
+        let key = format!("0x{}", hex::encode(position_id));
+        let owner_pub_key = match self.position_id_to_owner.get(key.as_bytes())? {
+            Some(pk) => pk,
+            None => return Ok(()),
+        };
 
-    // pub fn get_user_metadata(&self, owner_pub_key: &[u8]) -> Result<Option<Vec<u8>>> {
-    //     Ok(self.user_metadata.get(owner_pub_key)?.map(|iv| iv.to_vec()))
-    // }
+        let mut open_positions: Vec<Position> = self
+            .open_positions
+            .get_json(&owner_pub_key)?
+            .unwrap_or_default();
+        let Some(position) = open_positions.iter_mut().find(|p| p.position_id == key) else {
+            return Ok(());
+        };
+        position.mark_price = Some(mark_price);
+        position.unrealized_pnl = Some(unrealized_pnl);
+        let data = PositionData::Open(position.clone());
+
+        self.open_positions.insert_json(&owner_pub_key, &open_positions)?;
+        self.positions_by_id.insert_json(key.as_bytes(), &data)
+    }
+
+    // Records one event of a position's history. An event is identified by its
+    // transaction version and index, so reprocessing it is a no-op. Newly
+    // recorded events are published to the position's owner.
+    fn append_position_event(&mut self, position_id: &[u8], event: PositionEvent) -> Result<()> {
+        let event_key = position_event_key(position_id, event.version, event.index);
+        if self.position_events.get(&event_key)?.is_some() {
+            return Ok(());
+        }
+        self.position_events.insert_json(&event_key, &event)?;
+
+        let key = format!("0x{}", hex::encode(position_id));
+        if let Some(owner_pub_key) = self.position_id_to_owner.get(key.as_bytes())? {
+            self.updates.push(PositionUpdate {
+                owner: format!("0x{}", hex::encode(owner_pub_key)),
+                position_id: key,
+                event,
+            });
+        }
+        Ok(())
+    }
+
+    // Applies every staged tree in one multi-tree transaction.
+    fn commit(self) -> Result<Vec<PositionUpdate>> {
+        let (trees, batches): (Vec<Tree>, Vec<Batch>) = [
+            self.open_positions,
+            self.historical_positions,
+            self.unspent_notes,
+            self.spent_notes,
+            self.position_id_to_owner,
+            self.positions_by_id,
+            self.position_events,
+            self.note_receivers,
+            self.indexer_state,
+        ]
+        .into_iter()
+        .filter_map(StagedTree::into_batch)
+        .unzip();
+
+        if !trees.is_empty() {
+            trees
+                .as_slice()
+                .transaction(|views| {
+                    for (view, batch) in views.iter().zip(&batches) {
+                        view.apply_batch(batch)?;
+                    }
+                    Ok::<_, ConflictableTransactionError<Infallible>>(())
+                })
+                .map_err(|e| anyhow!("failed to apply batch: {}", e))?;
+        }
+        Ok(self.updates)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{Note, PositionEventKind};
+
+    fn add_note(note_nonce: u64) -> DbWrite {
+        DbWrite::AddNote(UnspentNote {
+            note_id: format!("0x{:016x}", note_nonce),
+            note: Note {
+                note_nonce,
+                receiver_hash: "0xabcd".to_string(),
+                value: "100".to_string(),
+            },
+        })
+    }
+
+    fn remove_note(note_nonce: u64) -> DbWrite {
+        DbWrite::RemoveNote {
+            note_id: note_nonce.to_be_bytes().to_vec(),
+        }
+    }
+
+    fn open_and_close(owner: &[u8], position_id: &str) -> [DbWrite; 2] {
+        let position = Position {
+            position_id: position_id.to_string(),
+            is_long: true,
+            entry_price: "100".to_string(),
+            margin: "10".to_string(),
+            size: "1".to_string(),
+            mark_price: None,
+            unrealized_pnl: None,
+        };
+        [
+            DbWrite::AddPosition {
+                owner_pub_key: owner.to_vec(),
+                position,
+            },
+            DbWrite::MoveToHistorical {
+                position_id: hex::decode(position_id.strip_prefix("0x").unwrap()).unwrap(),
+                status: PositionStatus::Closed,
+                final_pnl: "0".to_string(),
+                owner_address: "0xb0b".to_string(),
+            },
+        ]
+    }
+
+    fn close_position(db: &Database, owner: &[u8], position_id: &str) {
+        for write in open_and_close(owner, position_id) {
+            db.apply_batch(vec![write]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_history_cursor_is_stable_under_inserts() {
+        let db = Database::temporary().unwrap();
+        let owner = [1u8; 32];
+        for position_id in ["0x01", "0x02", "0x03"] {
+            close_position(&db, &owner, position_id);
+        }
+
+        let first = db.get_historical_positions(&owner, None, 2).unwrap();
+        close_position(&db, &owner, "0x04");
+        let cursor = HistoryCursor::decode(first.next_cursor.as_deref().unwrap()).unwrap();
+        let second = db.get_historical_positions(&owner, Some(&cursor), 2).unwrap();
+
+        let ids: Vec<_> = first
+            .items
+            .iter()
+            .chain(&second.items)
+            .map(|p| p.position.position_id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["0x03", "0x02", "0x01"]);
+        assert!(!second.has_more);
+        assert_eq!(second.total, Some(4));
+    }
+
+    #[test]
+    fn test_add_unspent_note_is_idempotent() {
+        let db = Database::temporary().unwrap();
+        db.apply_batch(vec![add_note(1)]).unwrap();
+        db.apply_batch(vec![add_note(1)]).unwrap();
+        assert_eq!(db.get_unspent_notes(&[0xab, 0xcd]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_spent_note_is_not_re_added() {
+        let db = Database::temporary().unwrap();
+        db.apply_batch(vec![add_note(1)]).unwrap();
+        db.apply_batch(vec![remove_note(1)]).unwrap();
+        db.apply_batch(vec![add_note(1)]).unwrap();
+        assert!(db.get_unspent_notes(&[0xab, 0xcd]).unwrap().is_empty());
+        assert!(db.get_unspent_note(&1u64.to_be_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_unspent_note_by_id() {
+        let db = Database::temporary().unwrap();
+        db.apply_batch(vec![add_note(1), add_note(2)]).unwrap();
+        let note = db.get_unspent_note(&2u64.to_be_bytes()).unwrap().unwrap();
+        assert_eq!(note.note.note_nonce, 2);
+        assert!(db.get_unspent_note(&3u64.to_be_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_note_index_is_backfilled_for_existing_notes() {
+        let db = Database::temporary().unwrap();
+        let DbWrite::AddNote(note) = add_note(1) else { unreachable!() };
+        // As written by a build that predates the note_id index.
+        db.unspent_notes
+            .insert([0xab, 0xcd], serde_json::to_vec(&vec![note]).unwrap())
+            .unwrap();
+        assert!(db.get_unspent_note(&1u64.to_be_bytes()).unwrap().is_none());
+
+        db.backfill_note_receivers().unwrap();
+        assert!(db.get_unspent_note(&1u64.to_be_bytes()).unwrap().is_some());
+        db.apply_batch(vec![remove_note(1)]).unwrap();
+        assert!(db.get_unspent_notes(&[0xab, 0xcd]).unwrap().is_empty());
+        assert!(db.note_receivers.is_empty());
+    }
+
+    #[test]
+    fn test_apply_batch_sees_its_own_writes() {
+        let db = Database::temporary().unwrap();
+        let owner = [1u8; 32];
+        let mut writes: Vec<DbWrite> = open_and_close(&owner, "0x01").into();
+        writes.extend([add_note(1), add_note(2), remove_note(1), DbWrite::SetLastProcessedVersion(7)]);
+        db.apply_batch(writes).unwrap();
+
+        assert!(db.get_open_positions(&owner).unwrap().is_empty());
+        assert_eq!(db.get_historical_positions(&owner, None, 10).unwrap().total, Some(1));
+        let notes = db.get_unspent_notes(&[0xab, 0xcd]).unwrap();
+        assert_eq!(notes.iter().map(|n| n.note.note_nonce).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(db.get_last_processed_version().unwrap(), Some(7));
+    }
+
+    #[test]
+    fn test_sync_status_estimates_lag_from_recent_throughput() {
+        let db = Database::temporary().unwrap();
+        assert_eq!(db.sync_status().unwrap().latest_ledger_version, None);
+
+        db.record_ledger_tip(LedgerTip { version: 1_500, timestamp_usecs: Some(10_000_000) });
+        db.record_ledger_tip(LedgerTip { version: 1_400, timestamp_usecs: Some(9_000_000) });
+        let status = db.sync_status().unwrap();
+        assert_eq!(status.latest_ledger_version, Some(1_500));
+        assert_eq!((status.lag_versions, status.estimated_lag_seconds), (None, None));
+
+        db.apply_batch(vec![DbWrite::SetLastProcessedVersion(1_000)]).unwrap();
+        assert_eq!(db.sync_status().unwrap().lag_versions, Some(500));
+        assert_eq!(db.sync_status().unwrap().estimated_lag_seconds, None);
+
+        db.record_ledger_tip(LedgerTip { version: 1_700, timestamp_usecs: Some(12_000_000) });
+        let status = db.sync_status().unwrap();
+        assert_eq!(status.latest_ledger_version, Some(1_700));
+        assert_eq!(status.lag_versions, Some(700));
+        assert_eq!(status.estimated_lag_seconds, Some(7.0));
+    }
+
+    #[test]
+    fn test_apply_batch_is_all_or_nothing() {
+        let db = Database::temporary().unwrap();
+        let mut bad_note = add_note(2);
+        if let DbWrite::AddNote(note) = &mut bad_note {
+            note.note.receiver_hash = "not hex".to_string();
+        }
+        let writes = vec![add_note(1), DbWrite::SetLastProcessedVersion(7), bad_note];
+        assert!(db.apply_batch(writes).is_err());
+
+        assert!(db.get_unspent_notes(&[0xab, 0xcd]).unwrap().is_empty());
+        assert_eq!(db.get_last_processed_version().unwrap(), None);
+    }
+
+    #[test]
+    fn test_position_events_are_keyed_by_version_and_index() {
+        let db = Database::temporary().unwrap();
+        let event = |position_id: Vec<u8>, version, index| DbWrite::AppendPositionEvent {
+            position_id,
+            event: PositionEvent {
+                kind: PositionEventKind::Closed,
+                version,
+                index,
+                data: serde_json::json!({}),
+            },
+        };
+        for _ in 0..2 {
+            db.apply_batch(vec![
+                event(vec![0x01], 9, 1),
+                event(vec![0x01], 9, 0),
+                event(vec![0x01], 3, 4),
+                event(vec![0x01, 0x02], 5, 0),
+            ])
+            .unwrap();
+        }
+
+        let events = db.get_position_events(&[0x01]).unwrap();
+        let keys: Vec<_> = events.iter().map(|e| (e.version, e.index)).collect();
+        assert_eq!(keys, vec![(3, 4), (9, 0), (9, 1)]);
+    }
 }
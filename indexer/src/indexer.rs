@@ -1,34 +1,112 @@
 ﻿// src/indexer.rs - Aptos implementation  
 use crate::{
-    config::Config,
-    database::Database,
-    models::{Position, PositionStatus, UnspentNote},
+    config::{normalize_address, Config, StartVersion},
+    database::{Database, DbWrite},
+    models::{LedgerTip, Position, PositionEvent, PositionEventKind, PositionStatus, UnspentNote},
 };
 use anyhow::{Result, anyhow};
 use reqwest::Client;
 use serde_json::Value;
-use std::sync::Arc;
-use tokio::time::{sleep, Duration};
+use std::{
+    fs::OpenOptions,
+    future::Future,
+    io::{BufRead, BufReader, Write},
+    sync::Arc,
+};
+use tokio::{
+    sync::watch,
+    time::{sleep, Duration, Instant},
+};
 
 const TRANSACTION_CHUNK_SIZE: u64 = 100;
 const POLLING_INTERVAL_SECONDS: u64 = 5;
 const RESTART_DELAY_SECONDS: u64 = 10;
-
-// Aptos module addresses (0x2 from our Move.toml)
-const NOX_MODULE_ADDRESS: &str = "0x0000000000000000000000000000000000000000000000000000000000000002";
+// How far behind the tip a cold start begins when no start version is requested.
+const DEFAULT_COLD_START_LAG: u64 = 100;
 
 pub async fn run_indexer(
     config: Arc<Config>,
     db: Arc<Database>,
     http_client: Arc<Client>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
+    if let Some(replay_path) = &config.replay_path {
+        replay_capture(&db, replay_path, &config.nox_module_addresses).await?;
+        println!("[Indexer] Replay finished; API stays up for inspection.");
+        let _ = shutdown.wait_for(|stop| *stop).await;
+        return Ok(());
+    }
+
+    // Last version whose writes are durably flushed; persisted so restarts resume.
+    let mut flushed_version = resume_version(&db, config.reindex)?;
+    if let Some(version) = flushed_version {
+        println!("[Indexer] Resuming after last processed version: {}", version);
+    }
     loop {
-        if let Err(e) = indexer_logic(config.clone(), db.clone(), http_client.clone()).await {
-            eprintln!(
-                "[Indexer] Error encountered: {}. Restarting in {} seconds...",
-                e, RESTART_DELAY_SECONDS
-            );
-            sleep(Duration::from_secs(RESTART_DELAY_SECONDS)).await;
+        let result = indexer_logic(
+            config.clone(),
+            db.clone(),
+            http_client.clone(),
+            &mut flushed_version,
+            &mut shutdown,
+        )
+        .await;
+        match result {
+            // indexer_logic only returns Ok once shutdown has been requested
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                eprintln!(
+                    "[Indexer] Error encountered: {}. Restarting in {} seconds...",
+                    e, RESTART_DELAY_SECONDS
+                );
+                if sleep_or_shutdown(Duration::from_secs(RESTART_DELAY_SECONDS), &mut shutdown).await {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// The persisted cursor to resume after, unless `--reindex` asks for a fresh cold
+// start. Handlers are idempotent, so a reindex can run over the existing data.
+fn resume_version(db: &Database, reindex: bool) -> Result<Option<u64>> {
+    if reindex {
+        println!("[Indexer] --reindex given; ignoring persisted cursor.");
+        return Ok(None);
+    }
+    db.get_last_processed_version()
+}
+
+// Sleeps for `duration`, waking early if shutdown is requested. Returns whether
+// shutdown has been requested.
+async fn sleep_or_shutdown(duration: Duration, shutdown: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = sleep(duration) => {}
+        _ = shutdown.changed() => {}
+    }
+    *shutdown.borrow()
+}
+
+// Awaits a node call unless shutdown is requested first, in which case it is
+// dropped and `None` is returned. A request in flight to a stalled node must
+// not keep SIGTERM from reaching the final flush.
+async fn unless_shutdown<F: Future>(
+    future: F,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Option<F::Output> {
+    tokio::pin!(future);
+    loop {
+        tokio::select! {
+            output = &mut future => return Some(output),
+            changed = shutdown.changed() => {
+                if *shutdown.borrow() {
+                    return None;
+                }
+                if changed.is_err() {
+                    // Sender is gone, so shutdown can no longer be requested.
+                    return Some(future.await);
+                }
+            }
         }
     }
 }
@@ -37,128 +115,459 @@ async fn indexer_logic(
     config: Arc<Config>,
     db: Arc<Database>,
     http_client: Arc<Client>,
+    flushed_version: &mut Option<u64>,
+    shutdown: &mut watch::Receiver<bool>,
 ) -> Result<()> {
     println!("[Indexer] Starting Aptos indexer...");
-    println!("[Indexer] Monitoring NOX modules at: {}", NOX_MODULE_ADDRESS);
+    println!("[Indexer] Monitoring NOX modules at: {}", config.nox_module_addresses.join(", "));
 
     // Get starting version
-    let ledger_info = get_ledger_info(&http_client, &config.rpc_url).await?;
-    let mut from_version = ledger_info["ledger_version"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid ledger version"))?
-        .parse::<u64>()?
-        .saturating_sub(100);
+    let Some(ledger_info) = unless_shutdown(get_ledger_info(&http_client, &config.rpc_url), shutdown).await
+    else {
+        return Ok(());
+    };
+    let ledger_info = ledger_info?;
+    let mut from_version = match *flushed_version {
+        Some(version) => version + 1,
+        None => cold_start_version(
+            ledger_version(&ledger_info)?,
+            config.start_version,
+            config.max_cold_start_lag,
+        ),
+    };
+    let mut buffer = WriteBuffer::new(
+        config.nox_module_addresses.clone(),
+        config.write_batch_events,
+        Duration::from_millis(config.write_flush_interval_ms),
+        *flushed_version,
+    );
     
     println!("[Indexer] Starting from version: {}", from_version);
 
     loop {
-        let latest_ledger = match get_ledger_info(&http_client, &config.rpc_url).await {
-            Ok(info) => info,
-            Err(e) => {
+        if *shutdown.borrow() {
+            *flushed_version = buffer.flush(&db).await?;
+            println!(
+                "[Indexer] Shutdown requested; writes flushed through version {:?}",
+                flushed_version
+            );
+            return Ok(());
+        }
+
+        let latest_ledger = match unless_shutdown(get_ledger_info(&http_client, &config.rpc_url), shutdown).await {
+            // Shutdown requested; the top of the loop flushes and returns.
+            None => continue,
+            Some(Ok(info)) => info,
+            Some(Err(e)) => {
                 eprintln!("[Indexer] Failed to get ledger info: {}", e);
-                sleep(Duration::from_secs(POLLING_INTERVAL_SECONDS)).await;
+                sleep_or_shutdown(Duration::from_secs(POLLING_INTERVAL_SECONDS), shutdown).await;
                 continue;
             }
         };
 
-        let latest_version = latest_ledger["ledger_version"]
-            .as_str()
-            .ok_or_else(|| anyhow!("Invalid ledger version"))?
-            .parse::<u64>()?;
+        let latest_version = match ledger_version(&latest_ledger) {
+            Ok(version) => version,
+            Err(e) => {
+                eprintln!("[Indexer] {}", e);
+                sleep_or_shutdown(Duration::from_secs(POLLING_INTERVAL_SECONDS), shutdown).await;
+                continue;
+            }
+        };
+        db.record_ledger_tip(LedgerTip {
+            version: latest_version,
+            timestamp_usecs: json_u64(&latest_ledger["ledger_timestamp"]),
+        });
         
         if from_version >= latest_version {
-            sleep(Duration::from_secs(POLLING_INTERVAL_SECONDS)).await;
+            if buffer.should_flush() {
+                *flushed_version = buffer.flush(&db).await?;
+            }
+            sleep_or_shutdown(Duration::from_secs(POLLING_INTERVAL_SECONDS), shutdown).await;
             continue;
         }
 
         let to_version = (from_version + TRANSACTION_CHUNK_SIZE - 1).min(latest_version);
 
-        match get_transactions(&http_client, &config.rpc_url, from_version, to_version).await {
-            Ok(transactions) => {
-                for transaction in transactions.as_array().unwrap_or(&vec![]) {
-                    if let Err(e) = process_transaction(&db, transaction).await {
-                        eprintln!("[Indexer] Error processing transaction: {}", e);
-                    }
+        let drained = unless_shutdown(
+            drain_range(
+                &http_client,
+                &config.rpc_url,
+                config.capture_path.as_deref(),
+                &mut buffer,
+                from_version,
+                to_version,
+            ),
+            shutdown,
+        )
+        .await;
+        match drained {
+            // Shutdown requested. A cancelled drain has only buffered whole
+            // pages, so the top of the loop can flush what it has.
+            None => continue,
+            Some(Ok(next_version)) => {
+                if buffer.should_flush() {
+                    *flushed_version = buffer.flush(&db).await?;
                 }
-                from_version = to_version + 1;
+                from_version = next_version;
             }
-            Err(e) => {
+            Some(Err(e)) => {
                 eprintln!("[Indexer] Error fetching transactions: {}", e);
-                sleep(Duration::from_secs(POLLING_INTERVAL_SECONDS)).await;
+                sleep_or_shutdown(Duration::from_secs(POLLING_INTERVAL_SECONDS), shutdown).await;
                 continue;
             }
         }
 
-        sleep(Duration::from_millis(500)).await;
+        sleep_or_shutdown(Duration::from_millis(500), shutdown).await;
+    }
+}
+
+// Picks the first version to index when there is nothing to resume from. A
+// numeric start older than `max_lag` versions behind the tip is clamped; FULL
+// is already bounded by the modules' deployment version and is taken as is.
+fn cold_start_version(latest_version: u64, requested: Option<StartVersion>, max_lag: u64) -> u64 {
+    let earliest_allowed = latest_version.saturating_sub(max_lag);
+    match requested {
+        Some(StartVersion::Full(genesis)) => genesis,
+        Some(StartVersion::At(version)) if version < earliest_allowed => {
+            eprintln!(
+                "[Indexer] WARNING: requested start version {} is more than {} versions behind the ledger ({}); clamping to {}. Raise INDEXER_MAX_COLD_START_LAG to backfill further.",
+                version, max_lag, latest_version, earliest_allowed
+            );
+            earliest_allowed
+        }
+        Some(StartVersion::At(version)) => version,
+        None => latest_version.saturating_sub(DEFAULT_COLD_START_LAG),
+    }
+}
+
+// Client for node calls with pooled, kept-alive connections, so each poll
+// reuses a warm connection instead of paying for a new TCP/TLS handshake.
+// Both timeouts bound every node call, which also bounds how long shutdown
+// can wait on an in-flight request.
+pub fn build_http_client(
+    pool_max_idle_per_host: usize,
+    tcp_keepalive: Option<Duration>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    http2_prior_knowledge: bool,
+) -> reqwest::Result<Client> {
+    let mut builder = Client::builder()
+        .pool_max_idle_per_host(pool_max_idle_per_host)
+        .tcp_keepalive(tcp_keepalive);
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    if http2_prior_knowledge {
+        builder.http2_prior_knowledge().build()
+    } else {
+        builder.build()
     }
 }
 
 async fn get_ledger_info(client: &Client, rpc_url: &str) -> Result<Value> {
     let url = format!("{}/", rpc_url);
-    let response = client.get(&url).send().await?;
+    let response = client.get(&url).send().await?.error_for_status()?;
     Ok(response.json().await?)
 }
 
 async fn get_transactions(client: &Client, rpc_url: &str, start: u64, end: u64) -> Result<Value> {
     let url = format!("{}/transactions?start={}&limit={}", rpc_url, start, end - start + 1);
-    let response = client.get(&url).send().await?;
-    Ok(response.json().await?)
+    let response = client.get(&url).send().await?.error_for_status()?;
+    let transactions: Value = response.json().await?;
+    // Anything but a list is an error body, not an empty page.
+    if !transactions.is_array() {
+        return Err(anyhow!("unexpected transactions response: {}", transactions));
+    }
+    Ok(transactions)
 }
 
-async fn process_transaction(db: &Database, transaction: &Value) -> Result<()> {  
-    let tx_type = transaction["type"].as_str().unwrap_or("");
-    if tx_type != "user_transaction" {
-        return Ok(());
+// Aptos encodes u64 values as JSON strings, but accept plain numbers too so a
+// node that changes representation doesn't wedge the indexer.
+fn json_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(s) => s.parse().ok(),
+        Value::Number(n) => n.as_u64(),
+        _ => None,
     }
+}
 
-    let payload = &transaction["payload"];
-    if let Some(function) = payload["function"].as_str() {
-        if !function.starts_with(NOX_MODULE_ADDRESS) {
-            return Ok(());
+// Like `json_u64`, but keeps the value as a decimal string (amounts may exceed u64).
+fn json_numeric_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        _ => "0".to_string(),
+    }
+}
+
+fn ledger_version(ledger_info: &Value) -> Result<u64> {
+    json_u64(&ledger_info["ledger_version"]).ok_or_else(|| {
+        anyhow!(
+            "Invalid ledger version in ledger info: {}",
+            ledger_info["ledger_version"]
+        )
+    })
+}
+
+// The version of the last transaction in a `/transactions` response.
+fn last_transaction_version(transactions: &Value) -> Option<u64> {
+    json_u64(&transactions.as_array()?.last()?["version"])
+}
+
+// Buffers every transaction in [from_version, to_version] and returns the next
+// version to fetch. The node caps `limit` and may return fewer transactions
+// than requested, so we keep asking from just past the last version actually
+// received instead of assuming the whole range arrived. An empty response
+// stops early; the caller retries from the returned version.
+async fn drain_range(
+    client: &Client,
+    rpc_url: &str,
+    capture_path: Option<&str>,
+    buffer: &mut WriteBuffer,
+    mut from_version: u64,
+    to_version: u64,
+) -> Result<u64> {
+    while from_version <= to_version {
+        let transactions = get_transactions(client, rpc_url, from_version, to_version).await?;
+        let Some(last_version) = last_transaction_version(&transactions) else {
+            break;
+        };
+        if let Some(capture_path) = capture_path {
+            append_capture(capture_path, &transactions)?;
         }
-    } else {
-        return Ok(());
+        for transaction in transactions.as_array().unwrap_or(&vec![]) {
+            buffer.push(transaction);
+        }
+        buffer.advance_to(last_version);
+        from_version = last_version + 1;
+    }
+    Ok(from_version)
+}
+
+// Holds NOX transactions whose writes have not reached the database yet.
+// `flushed_version` only moves once `flush` has applied and fsynced them, so a
+// resume never skips over events that were merely buffered.
+struct WriteBuffer {
+    modules: Vec<String>,
+    pending: Vec<Value>,
+    pending_events: usize,
+    buffered_version: Option<u64>,
+    flushed_version: Option<u64>,
+    max_events: usize,
+    flush_interval: Duration,
+    last_flush: Instant,
+}
+
+impl WriteBuffer {
+    fn new(
+        modules: Vec<String>,
+        max_events: usize,
+        flush_interval: Duration,
+        flushed_version: Option<u64>,
+    ) -> Self {
+        Self {
+            modules,
+            pending: Vec::new(),
+            pending_events: 0,
+            buffered_version: flushed_version,
+            flushed_version,
+            max_events,
+            flush_interval,
+            last_flush: Instant::now(),
+        }
+    }
+
+    fn push(&mut self, transaction: &Value) {
+        if !is_nox_transaction(transaction, &self.modules) {
+            return;
+        }
+        self.pending_events += transaction["events"].as_array().map_or(0, Vec::len);
+        self.pending.push(transaction.clone());
+    }
+
+    // Marks every version up to `version` as buffered.
+    fn advance_to(&mut self, version: u64) {
+        self.buffered_version = Some(version);
+    }
+
+    fn should_flush(&self) -> bool {
+        self.buffered_version != self.flushed_version
+            && (self.pending_events >= self.max_events
+                || self.last_flush.elapsed() >= self.flush_interval)
+    }
+
+    // Applies the buffered writes and the advanced cursor as one batch, so a
+    // failed flush leaves the cursor where the data is and can simply be redone.
+    async fn flush(&mut self, db: &Database) -> Result<Option<u64>> {
+        let mut writes = Vec::new();
+        for transaction in self.pending.drain(..) {
+            collect_transaction_writes(&transaction, &self.modules, &mut writes);
+        }
+        if let Some(version) = self.buffered_version {
+            writes.push(DbWrite::SetLastProcessedVersion(version));
+        }
+        db.apply_batch(writes)?;
+        db.flush_async().await?;
+        self.pending_events = 0;
+        self.flushed_version = self.buffered_version;
+        self.last_flush = Instant::now();
+        Ok(self.flushed_version)
+    }
+}
+
+// Appends each transaction of a fetched chunk as one JSON line.
+fn append_capture(path: &str, transactions: &Value) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for transaction in transactions.as_array().unwrap_or(&vec![]) {
+        serde_json::to_writer(&mut file, transaction)?;
+        file.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// Feeds a capture file back through the same pipeline the live indexer uses.
+async fn replay_capture(db: &Database, path: &str, modules: &[String]) -> Result<()> {
+    println!("[Indexer] Replaying captured transactions from: {}", path);
+    let reader = BufReader::new(std::fs::File::open(path)?);
+    let mut replayed = 0u64;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let transaction: Value = serde_json::from_str(&line)?;
+        if let Err(e) = process_transaction(db, &transaction, modules).await {
+            eprintln!("[Indexer] Error processing transaction: {}", e);
+        }
+        replayed += 1;
+    }
+    println!("[Indexer] Replayed {} transactions", replayed);
+    Ok(())
+}
+
+// Normalized address part of a `{address}::{module}::{name}` identifier.
+fn module_address(qualified_name: &str) -> Option<String> {
+    qualified_name
+        .split_once("::")
+        .map(|(address, _)| normalize_address(address))
+}
+
+fn is_nox_transaction(transaction: &Value, modules: &[String]) -> bool {
+    transaction["type"].as_str() == Some("user_transaction")
+        && transaction["payload"]["function"]
+            .as_str()
+            .and_then(module_address)
+            .is_some_and(|address| modules.contains(&address))
+}
+
+async fn process_transaction(db: &Database, transaction: &Value, modules: &[String]) -> Result<()> {  
+    let mut writes = Vec::new();
+    collect_transaction_writes(transaction, modules, &mut writes);
+    db.apply_batch(writes)
+}
+
+// Appends the writes for every NOX event in `transaction`. A malformed event is
+// logged and contributes nothing, so it can't fail the rest of its chunk.
+fn collect_transaction_writes(transaction: &Value, modules: &[String], writes: &mut Vec<DbWrite>) {
+    if !is_nox_transaction(transaction, modules) {
+        return;
     }
 
+    let version = json_u64(&transaction["version"]).unwrap_or(0);
+
     if let Some(events) = transaction["events"].as_array() {
-        for event in events {
-            if let Err(e) = process_event(db, event).await {
-                eprintln!("[Indexer] Error processing event: {}", e);
+        for (index, event) in (0u64..).zip(events) {
+            let mut event_writes = Vec::new();
+            match process_event(event, version, index, modules, &mut event_writes) {
+                Ok(()) => writes.append(&mut event_writes),
+                Err(e) => eprintln!("[Indexer] Error processing event: {}", e),
             }
         }
     }
+}
 
-    Ok(())
+// `module::struct` part of an event type emitted by one of the configured NOX
+// modules. Events from any other address are rejected even when their name
+// matches, so a look-alike module can't inject notes or positions.
+fn nox_event_name<'a>(event_type: &'a str, modules: &[String]) -> Option<&'a str> {
+    let (address, name) = event_type.split_once("::")?;
+    modules.contains(&normalize_address(address)).then_some(name)
 }
 
-async fn process_event(db: &Database, event: &Value) -> Result<()> {
+fn process_event(
+    event: &Value,
+    version: u64,
+    index: u64,
+    modules: &[String],
+    writes: &mut Vec<DbWrite>,
+) -> Result<()> {
     let event_type = event["type"].as_str().unwrap_or("");
     let event_data = &event["data"];
+    let Some(event_name) = nox_event_name(event_type, modules) else {
+        return Ok(());
+    };
 
-    match event_type {
-        s if s.contains("token_pool::NoteCreated") => {
-            handle_note_created(db, event_data).await
+    match event_name {
+        "token_pool::NoteCreated" => {
+            handle_note_created(event_data, writes)
+        }
+        "token_pool::NoteClaimed" => {
+            handle_note_claimed(event_data, writes)
         }
-        s if s.contains("token_pool::NoteClaimed") => {
-            handle_note_claimed(db, event_data).await  
+        "privacy_proxy::PositionOpened" => {
+            handle_position_opened(event_data, writes)?;
+            record_position_event(PositionEventKind::Opened, version, index, event_data, writes)
         }
-        s if s.contains("privacy_proxy::PositionOpened") => {
-            handle_position_opened(db, event_data).await
+        // Mark ticks only refresh the open position; they would swamp its history.
+        "clearing_house::PositionUpdated" => {
+            handle_position_updated(event_data, writes)
         }
-        s if s.contains("clearing_house::PositionClosed") => {
-            handle_position_closed(db, event_data).await
+        // Closing events are recorded first, while the position still has an
+        // owner to publish them to.
+        "clearing_house::PositionClosed" => {
+            record_position_event(PositionEventKind::Closed, version, index, event_data, writes)?;
+            handle_position_closed(event_data, writes)
         }
-        s if s.contains("clearing_house::PositionLiquidated") => {
-            handle_position_liquidated(db, event_data).await
+        "clearing_house::PositionLiquidated" => {
+            record_position_event(PositionEventKind::Liquidated, version, index, event_data, writes)?;
+            handle_position_liquidated(event_data, writes)
         }
         _ => Ok(())
     }
 }
 
-async fn handle_note_created(db: &Database, event_data: &Value) -> Result<()> {
-    let note_nonce = event_data["note_nonce"].as_u64().unwrap_or(0);
+fn record_position_event(
+    kind: PositionEventKind,
+    version: u64,
+    index: u64,
+    event_data: &Value,
+    writes: &mut Vec<DbWrite>,
+) -> Result<()> {
+    let position_id = event_data["position_id"].as_str().unwrap_or("");
+    let position_id_bytes = hex::decode(position_id.strip_prefix("0x").unwrap_or(position_id))?;
+    writes.push(DbWrite::AppendPositionEvent {
+        position_id: position_id_bytes,
+        event: PositionEvent {
+            kind,
+            version,
+            index,
+            data: event_data.clone(),
+        },
+    });
+    Ok(())
+}
+
+fn handle_note_created(event_data: &Value, writes: &mut Vec<DbWrite>) -> Result<()> {
+    let note_nonce = json_u64(&event_data["note_nonce"]).unwrap_or(0);
     let receiver_hash = event_data["receiver_hash"].as_str().unwrap_or("");
-    let amount = event_data["amount"].as_str().unwrap_or("0");
+    let amount = json_numeric_string(&event_data["amount"]);
+
+    // Rejected here rather than when the batch is applied, where it would fail
+    // the whole chunk.
+    hex::decode(receiver_hash.strip_prefix("0x").unwrap_or(receiver_hash))?;
 
     let note_id = format!("0x{:016x}", note_nonce);
     
@@ -167,60 +576,537 @@ async fn handle_note_created(db: &Database, event_data: &Value) -> Result<()> {
         note: crate::models::Note {
             note_nonce,
             receiver_hash: receiver_hash.to_string(),
-            value: amount.to_string(),
+            value: amount,
         },
     };
     
-    db.add_unspent_note(&unspent_note)?;
+    writes.push(DbWrite::AddNote(unspent_note));
     Ok(())
 }
 
-async fn handle_note_claimed(db: &Database, event_data: &Value) -> Result<()> {
+fn handle_note_claimed(event_data: &Value, writes: &mut Vec<DbWrite>) -> Result<()> {
     let note_id = event_data["note_id"].as_str().unwrap_or("");
     let note_id_bytes = hex::decode(note_id.strip_prefix("0x").unwrap_or(note_id))?;
-    db.remove_unspent_note(&note_id_bytes)?;
+    writes.push(DbWrite::RemoveNote { note_id: note_id_bytes });
     Ok(())
 }
 
-async fn handle_position_opened(db: &Database, event_data: &Value) -> Result<()> {
+fn handle_position_opened(event_data: &Value, writes: &mut Vec<DbWrite>) -> Result<()> {
     let position_id = event_data["position_id"].as_str().unwrap_or("");
     let is_long = event_data["is_long"].as_bool().unwrap_or(false);
-    let entry_price = event_data["entry_price"].as_str().unwrap_or("0");
-    let margin = event_data["margin"].as_str().unwrap_or("0");
-    let size = event_data["size"].as_str().unwrap_or("0");
+    let entry_price = json_numeric_string(&event_data["entry_price"]);
+    let margin = json_numeric_string(&event_data["margin"]);
+    let size = json_numeric_string(&event_data["size"]);
     let owner_hash = event_data["owner_hash"].as_str().unwrap_or("0");
 
     let position = Position {
         position_id: position_id.to_string(),
         is_long,
-        entry_price: entry_price.to_string(),
-        margin: margin.to_string(),
-        size: size.to_string(),
+        entry_price,
+        margin,
+        size,
+        mark_price: None,
+        unrealized_pnl: None,
     };
     
     let owner_key_bytes = hex::decode(owner_hash.strip_prefix("0x").unwrap_or(owner_hash))?;
     let mut owner_id = [0u8; 32];
     owner_id[..owner_key_bytes.len().min(32)].copy_from_slice(&owner_key_bytes[..owner_key_bytes.len().min(32)]);
     
-    db.add_open_position(&owner_id, position)?;
+    writes.push(DbWrite::AddPosition {
+        owner_pub_key: owner_id.to_vec(),
+        position,
+    });
+    Ok(())
+}
+
+fn handle_position_updated(event_data: &Value, writes: &mut Vec<DbWrite>) -> Result<()> {
+    let position_id = event_data["position_id"].as_str().unwrap_or("");
+    let mark_price = json_numeric_string(&event_data["mark_price"]);
+    let unrealized_pnl = json_numeric_string(&event_data["unrealized_pnl"]);
+
+    let position_id_bytes = hex::decode(position_id.strip_prefix("0x").unwrap_or(position_id))?;
+    writes.push(DbWrite::UpdateMark {
+        position_id: position_id_bytes,
+        mark_price,
+        unrealized_pnl,
+    });
     Ok(())
 }
 
-async fn handle_position_closed(db: &Database, event_data: &Value) -> Result<()> {
+fn handle_position_closed(event_data: &Value, writes: &mut Vec<DbWrite>) -> Result<()> {
     let position_id = event_data["position_id"].as_str().unwrap_or("");
-    let pnl = event_data["pnl"].as_str().unwrap_or("0");
+    let pnl = json_numeric_string(&event_data["pnl"]);
     let user = event_data["user"].as_str().unwrap_or("unknown");
 
     let position_id_bytes = hex::decode(position_id.strip_prefix("0x").unwrap_or(position_id))?;
-    db.move_to_historical(&position_id_bytes, PositionStatus::Closed, pnl.to_string(), user.to_string())?;
+    writes.push(DbWrite::MoveToHistorical {
+        position_id: position_id_bytes,
+        status: PositionStatus::Closed,
+        final_pnl: pnl,
+        owner_address: user.to_string(),
+    });
     Ok(())
 }
 
-async fn handle_position_liquidated(db: &Database, event_data: &Value) -> Result<()> {
+fn handle_position_liquidated(event_data: &Value, writes: &mut Vec<DbWrite>) -> Result<()> {
     let position_id = event_data["position_id"].as_str().unwrap_or("");
     let user = event_data["user"].as_str().unwrap_or("unknown");
 
     let position_id_bytes = hex::decode(position_id.strip_prefix("0x").unwrap_or(position_id))?;
-    db.move_to_historical(&position_id_bytes, PositionStatus::Liquidated, "Liquidated".to_string(), user.to_string())?;
+    writes.push(DbWrite::MoveToHistorical {
+        position_id: position_id_bytes,
+        status: PositionStatus::Liquidated,
+        final_pnl: "Liquidated".to_string(),
+        owner_address: user.to_string(),
+    });
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::{collections::HashSet, net::SocketAddr};
+
+    const NOX_MODULE_ADDRESS: &str = "0x0000000000000000000000000000000000000000000000000000000000000002";
+
+    fn modules() -> Vec<String> {
+        vec![NOX_MODULE_ADDRESS.to_string()]
+    }
+
+    fn note_created_transaction(note_nonce: u64) -> Value {
+        json!({
+            "type": "user_transaction",
+            "payload": { "function": format!("{}::token_pool::deposit", NOX_MODULE_ADDRESS) },
+            "events": [{
+                "type": format!("{}::token_pool::NoteCreated", NOX_MODULE_ADDRESS),
+                "data": { "note_nonce": note_nonce, "receiver_hash": "0xabcd", "amount": "100" }
+            }]
+        })
+    }
+
+    fn position_transaction(version: u64, event_type: &str, data: Value) -> Value {
+        json!({
+            "type": "user_transaction",
+            "version": version.to_string(),
+            "payload": { "function": format!("{}::privacy_proxy::open_position", NOX_MODULE_ADDRESS) },
+            "events": [{ "type": format!("{}::{}", NOX_MODULE_ADDRESS, event_type), "data": data }]
+        })
+    }
+
+    #[tokio::test]
+    async fn test_position_lifecycle_events_are_recorded_in_order() {
+        let db = Database::temporary().unwrap();
+        let opened = position_transaction(
+            7,
+            "privacy_proxy::PositionOpened",
+            json!({ "position_id": "0x01", "is_long": true, "entry_price": "100", "margin": "10", "size": "1", "owner_hash": "0x0a" }),
+        );
+        let closed = position_transaction(
+            9,
+            "clearing_house::PositionClosed",
+            json!({ "position_id": "0x01", "pnl": "5", "user": "0xb0b" }),
+        );
+        let updated = position_transaction(
+            8,
+            "clearing_house::PositionUpdated",
+            json!({ "position_id": "0x01", "mark_price": "104", "unrealized_pnl": "4" }),
+        );
+        for transaction in [&opened, &updated, &closed, &closed] {
+            process_transaction(&db, transaction, &modules()).await.unwrap();
+        }
+
+        let events = db.get_position_events(&[0x01]).unwrap();
+        let sequence: Vec<_> = events.iter().map(|e| (e.kind.clone(), e.version)).collect();
+        assert_eq!(
+            sequence,
+            vec![(PositionEventKind::Opened, 7), (PositionEventKind::Closed, 9)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_position_updates_set_unrealized_pnl() {
+        let db = Database::temporary().unwrap();
+        let opened = position_transaction(
+            7,
+            "privacy_proxy::PositionOpened",
+            json!({ "position_id": "0x01", "is_long": true, "entry_price": "100", "margin": "10", "size": "1", "owner_hash": "0x0a" }),
+        );
+        let updated = position_transaction(
+            8,
+            "clearing_house::PositionUpdated",
+            json!({ "position_id": "0x01", "mark_price": "104", "unrealized_pnl": "4" }),
+        );
+        process_transaction(&db, &opened, &modules()).await.unwrap();
+        process_transaction(&db, &updated, &modules()).await.unwrap();
+
+        let mut owner = [0u8; 32];
+        owner[0] = 0x0a;
+        let position = &db.get_open_positions(&owner).unwrap()[0];
+        assert_eq!(position.mark_price.as_deref(), Some("104"));
+        assert_eq!(position.unrealized_pnl.as_deref(), Some("4"));
+    }
+
+    #[tokio::test]
+    async fn test_position_events_are_published_with_owner() {
+        let db = Database::temporary().unwrap();
+        let mut updates = db.subscribe_position_updates();
+        let opened = position_transaction(
+            7,
+            "privacy_proxy::PositionOpened",
+            json!({ "position_id": "0x01", "is_long": true, "entry_price": "100", "margin": "10", "size": "1", "owner_hash": "0x0a" }),
+        );
+        let closed = position_transaction(
+            9,
+            "clearing_house::PositionClosed",
+            json!({ "position_id": "0x01", "pnl": "5", "user": "0xb0b" }),
+        );
+        for transaction in [&opened, &closed, &closed] {
+            process_transaction(&db, transaction, &modules()).await.unwrap();
+        }
+
+        let owner = format!("0x0a{}", "00".repeat(31));
+        for kind in [PositionEventKind::Opened, PositionEventKind::Closed] {
+            let update = updates.try_recv().unwrap();
+            assert_eq!((update.owner.as_str(), update.event.kind), (owner.as_str(), kind));
+        }
+        assert!(updates.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reprocessing_does_not_duplicate_or_resurrect() {
+        let db = Database::temporary().unwrap();
+        let opened = position_transaction(
+            7,
+            "privacy_proxy::PositionOpened",
+            json!({ "position_id": "0x01", "is_long": true, "entry_price": "100", "margin": "10", "size": "1", "owner_hash": "0x0a" }),
+        );
+        let closed = position_transaction(
+            9,
+            "clearing_house::PositionClosed",
+            json!({ "position_id": "0x01", "pnl": "5", "user": "0xb0b" }),
+        );
+        let note = note_created_transaction(1);
+        for _ in 0..2 {
+            for transaction in [&opened, &closed, &note] {
+                process_transaction(&db, transaction, &modules()).await.unwrap();
+            }
+        }
+
+        let mut owner = [0u8; 32];
+        owner[0] = 0x0a;
+        assert!(db.get_open_positions(&owner).unwrap().is_empty());
+        assert_eq!(db.get_historical_positions(&owner, None, 10).unwrap().items.len(), 1);
+        assert_eq!(db.get_unspent_notes(&[0xab, 0xcd]).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replay_reproduces_live_state() {
+        let transactions = json!([note_created_transaction(1), note_created_transaction(2)]);
+
+        let live_db = Database::temporary().unwrap();
+        for transaction in transactions.as_array().unwrap() {
+            process_transaction(&live_db, transaction, &modules()).await.unwrap();
+        }
+
+        let capture = std::env::temp_dir().join(format!("indexer-capture-{}.jsonl", std::process::id()));
+        let capture = capture.to_str().unwrap();
+        let _ = std::fs::remove_file(capture);
+        append_capture(capture, &transactions).unwrap();
+
+        let replay_db = Database::temporary().unwrap();
+        replay_capture(&replay_db, capture, &modules()).await.unwrap();
+        std::fs::remove_file(capture).unwrap();
+
+        let receiver_hash = hex::decode("abcd").unwrap();
+        let live_notes = live_db.get_unspent_notes(&receiver_hash).unwrap();
+        let replayed_notes = replay_db.get_unspent_notes(&receiver_hash).unwrap();
+        assert_eq!(replayed_notes.len(), 2);
+        assert_eq!(
+            serde_json::to_value(&live_notes).unwrap(),
+            serde_json::to_value(&replayed_notes).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_transactions_match_any_configured_module() {
+        let clearing_house = normalize_address("0xC1EA");
+        let modules = vec![normalize_address("0x2"), clearing_house.clone()];
+        let call = |function: String| json!({ "type": "user_transaction", "payload": { "function": function } });
+
+        assert!(is_nox_transaction(&call("0x2::token_pool::deposit".to_string()), &modules));
+        assert!(is_nox_transaction(&call(format!("{}::clearing_house::close", clearing_house)), &modules));
+        assert!(is_nox_transaction(&call("0xc1ea::clearing_house::close".to_string()), &modules));
+        assert!(!is_nox_transaction(&call("0x3::token_pool::deposit".to_string()), &modules));
+        assert!(!is_nox_transaction(&call("0x20::token_pool::deposit".to_string()), &modules));
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_event_types_are_ignored() {
+        let db = Database::temporary().unwrap();
+        let data = json!({ "note_nonce": 1, "receiver_hash": "0xabcd", "amount": "100" });
+        let transaction = json!({
+            "type": "user_transaction",
+            "version": "1",
+            "payload": { "function": format!("{}::token_pool::deposit", NOX_MODULE_ADDRESS) },
+            "events": [
+                { "type": "0xbad::token_pool::NoteCreated", "data": data },
+                { "type": format!("{}::fake_token_pool::NoteCreated", NOX_MODULE_ADDRESS), "data": data },
+                { "type": format!("{}::token_pool::NoteCreatedV2", NOX_MODULE_ADDRESS), "data": data },
+            ]
+        });
+        process_transaction(&db, &transaction, &modules()).await.unwrap();
+        assert!(db.get_unspent_notes(&[0xab, 0xcd]).unwrap().is_empty());
+
+        process_transaction(&db, &note_created_transaction(1), &modules()).await.unwrap();
+        assert_eq!(db.get_unspent_notes(&[0xab, 0xcd]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_ledger_version_accepts_string_or_number() {
+        assert_eq!(ledger_version(&json!({ "ledger_version": "42" })).unwrap(), 42);
+        assert_eq!(ledger_version(&json!({ "ledger_version": 42 })).unwrap(), 42);
+        assert!(ledger_version(&json!({ "chain_id": 2 })).is_err());
+        assert!(ledger_version(&json!({ "ledger_version": "latest" })).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_numeric_event_fields_are_stored_as_strings() {
+        let db = Database::temporary().unwrap();
+        let opened = position_transaction(
+            7,
+            "privacy_proxy::PositionOpened",
+            json!({ "position_id": "0x01", "is_long": true, "entry_price": 100, "margin": "10", "size": 2, "owner_hash": "0x0a" }),
+        );
+        process_transaction(&db, &opened, &modules()).await.unwrap();
+
+        let mut owner = [0u8; 32];
+        owner[0] = 0x0a;
+        let position = &db.get_open_positions(&owner).unwrap()[0];
+        assert_eq!(position.entry_price, "100");
+        assert_eq!(position.size, "2");
+    }
+
+    #[test]
+    fn test_reindex_ignores_persisted_cursor() {
+        let db = Database::temporary().unwrap();
+        assert_eq!(resume_version(&db, false).unwrap(), None);
+        db.apply_batch(vec![DbWrite::SetLastProcessedVersion(20)]).unwrap();
+        assert_eq!(resume_version(&db, false).unwrap(), Some(20));
+        assert_eq!(resume_version(&db, true).unwrap(), None);
+    }
+
+    #[test]
+    fn test_cold_start_version_clamps_to_max_lag() {
+        assert_eq!(cold_start_version(10_000, None, 1_000), 9_900);
+        assert_eq!(cold_start_version(10_000, Some(StartVersion::At(9_500)), 1_000), 9_500);
+        assert_eq!(cold_start_version(10_000, Some(StartVersion::At(10)), 1_000), 9_000);
+    }
+
+    #[test]
+    fn test_cold_start_version_does_not_clamp_full_backfill() {
+        assert_eq!(cold_start_version(10_000_000, Some(StartVersion::Full(10)), 1_000), 10);
+    }
+
+    #[tokio::test]
+    async fn test_drain_range_handles_short_responses() {
+        use axum::{extract::Query, routing::get, Json, Router};
+        use std::collections::HashMap;
+
+        // A node that never returns more than two transactions per request.
+        async fn transactions(Query(params): Query<HashMap<String, u64>>) -> Json<Value> {
+            let start = params["start"];
+            let count = params["limit"].min(2);
+            Json(json!((start..start + count)
+                .map(|version| {
+                    let mut transaction = note_created_transaction(version);
+                    transaction["version"] = json!(version.to_string());
+                    transaction
+                })
+                .collect::<Vec<_>>()))
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(listener, Router::new().route("/transactions", get(transactions)))
+                .await
+                .unwrap();
+        });
+
+        let mut buffer = WriteBuffer::new(modules(), 1, Duration::ZERO, None);
+        let next_version = drain_range(&Client::new(), &rpc_url, None, &mut buffer, 1, 5)
+            .await
+            .unwrap();
+        assert_eq!(next_version, 6);
+        assert_eq!(buffer.pending.len(), 5);
+        assert_eq!(buffer.buffered_version, Some(5));
+    }
+
+    #[tokio::test]
+    async fn test_drain_range_fails_on_node_error() {
+        use axum::{http::StatusCode, routing::get, Json, Router};
+
+        // A pruning fullnode asked for versions it no longer has.
+        let pruned = || async {
+            (
+                StatusCode::GONE,
+                Json(json!({ "message": "version pruned", "error_code": "version_pruned" })),
+            )
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(listener, Router::new().route("/transactions", get(pruned)))
+                .await
+                .unwrap();
+        });
+
+        let mut buffer = WriteBuffer::new(modules(), 1, Duration::ZERO, None);
+        let result = drain_range(&Client::new(), &rpc_url, None, &mut buffer, 1, 5).await;
+        assert!(result.is_err());
+        assert!(buffer.pending.is_empty());
+        assert_eq!(buffer.buffered_version, None);
+    }
+
+    // A mock node answering ledger-info requests that records the peer address
+    // of every connection it serves.
+    async fn connection_counting_node() -> (String, Arc<std::sync::Mutex<HashSet<SocketAddr>>>) {
+        use axum::{extract::ConnectInfo, routing::get, Json, Router};
+
+        let peers = Arc::new(std::sync::Mutex::new(HashSet::new()));
+        let app = Router::new().route(
+            "/",
+            get({
+                let peers = Arc::clone(&peers);
+                move |ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                    peers.lock().unwrap().insert(peer);
+                    Json(json!({ "ledger_version": "1" }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        });
+        (rpc_url, peers)
+    }
+
+    // Issues `requests` serial polls the way the main loop does, on the shared
+    // client if given and on a new client per request otherwise. Returns the
+    // mean latency per request.
+    async fn serial_polls(rpc_url: &str, shared: Option<&Client>, requests: u32) -> Duration {
+        let started = Instant::now();
+        for _ in 0..requests {
+            let client = shared.cloned().unwrap_or_default();
+            get_ledger_info(&client, rpc_url).await.unwrap();
+        }
+        started.elapsed() / requests
+    }
+
+    fn default_node_client() -> Client {
+        build_http_client(
+            usize::MAX,
+            Some(Duration::from_secs(30)),
+            Some(Duration::from_secs(30)),
+            Some(Duration::from_secs(10)),
+            false,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_shared_client_reuses_one_connection() {
+        let (rpc_url, peers) = connection_counting_node().await;
+        serial_polls(&rpc_url, Some(&default_node_client()), 5).await;
+        assert_eq!(peers.lock().unwrap().len(), 1);
+
+        peers.lock().unwrap().clear();
+        serial_polls(&rpc_url, None, 5).await;
+        assert_eq!(peers.lock().unwrap().len(), 5);
+    }
+
+    // Serial vs pooled latency against a local node; the numbers are noted next
+    // to the HTTP defaults in config.rs. Run with
+    // `cargo test bench_ -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_serial_poll_latency_shared_vs_new_client() {
+        const REQUESTS: u32 = 200;
+        let (rpc_url, _) = connection_counting_node().await;
+        let shared = default_node_client();
+        // Warm up the shared connection so only steady-state polls are timed.
+        serial_polls(&rpc_url, Some(&shared), 1).await;
+
+        let shared_latency = serial_polls(&rpc_url, Some(&shared), REQUESTS).await;
+        let new_client_latency = serial_polls(&rpc_url, None, REQUESTS).await;
+        println!(
+            "mean poll latency over {REQUESTS} serial requests: shared client {shared_latency:?}, new client per request {new_client_latency:?}"
+        );
+        assert!(shared_latency < new_client_latency);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_interrupts_pending_node_call() {
+        let (shutdown_tx, mut shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            shutdown_tx.send_replace(true);
+        });
+        let stalled = std::future::pending::<Result<Value>>();
+        let outcome = tokio::time::timeout(
+            Duration::from_secs(5),
+            unless_shutdown(stalled, &mut shutdown),
+        )
+        .await;
+        assert!(matches!(outcome, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_node_client_times_out_stalled_node() {
+        use axum::{routing::get, Router};
+
+        let app = Router::new().route(
+            "/",
+            get(|| async { tokio::time::sleep(Duration::from_secs(30)).await }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = build_http_client(
+            usize::MAX,
+            None,
+            Some(Duration::from_millis(200)),
+            Some(Duration::from_millis(200)),
+            false,
+        )
+        .unwrap();
+        let started = Instant::now();
+        assert!(get_ledger_info(&client, &rpc_url).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_write_buffer_cursor_tracks_flushed_events() {
+        let db = Database::temporary().unwrap();
+        let mut buffer = WriteBuffer::new(modules(), 2, Duration::from_secs(3600), None);
+
+        buffer.push(&note_created_transaction(1));
+        buffer.advance_to(10);
+        assert!(!buffer.should_flush());
+        assert_eq!(buffer.flushed_version, None);
+        assert_eq!(db.get_last_processed_version().unwrap(), None);
+        assert!(db.get_unspent_notes(&[0xab, 0xcd]).unwrap().is_empty());
+
+        buffer.push(&note_created_transaction(2));
+        buffer.advance_to(20);
+        assert!(buffer.should_flush());
+        assert_eq!(buffer.flush(&db).await.unwrap(), Some(20));
+        assert_eq!(db.get_last_processed_version().unwrap(), Some(20));
+        assert_eq!(db.get_unspent_notes(&[0xab, 0xcd]).unwrap().len(), 2);
+    }
+}
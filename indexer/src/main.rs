@@ -7,8 +7,38 @@ mod models;
 use anyhow::Result;
 use config::Config;
 use database::Database;
-use reqwest::Client;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::watch, task::JoinError};
+
+// Resolves on Ctrl-C (SIGINT) or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                eprintln!("[WARN] Could not install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+fn task_result(result: std::result::Result<Result<()>, JoinError>) -> Result<()> {
+    result?
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -17,53 +47,85 @@ async fn main() -> Result<()> {
     println!("✅ Configuration loaded.");
 
     // 2. Initialize the database
-    let db = Arc::new(Database::new(&config.db_path)?);
+    let db = Arc::new(Database::new(&config.db_path, config.db_flush_every_ms)?);
     println!("✅ Database connected at: {}", &config.db_path);
 
     // 3. Initialize HTTP client for Aptos REST API
-    let http_client = Arc::new(Client::new());
+    // A zero duration in the config disables that setting.
+    let secs = |secs: u64| Some(Duration::from_secs(secs)).filter(|d| !d.is_zero());
+    let http_client = Arc::new(indexer::build_http_client(
+        config.http_pool_max_idle_per_host,
+        secs(config.http_tcp_keepalive_secs),
+        secs(config.http_timeout_secs),
+        secs(config.http_connect_timeout_secs),
+        config.http2_prior_knowledge,
+    )?);
     println!("✅ HTTP client created for Aptos REST API.");
 
-    // Test connection by getting ledger info
-    println!("config.rpc_url {}", config.rpc_url);
-    let test_url = format!("{}/", config.rpc_url);
-    let _ledger_info = match http_client.get(&test_url).send().await {
-        Ok(response) => {
-            if response.status().is_success() {
-                println!("✅ Successfully connected to Aptos node");
-                response.json::<serde_json::Value>().await.ok()
-            } else {
-                eprintln!("[FATAL INDEXER ERROR] Failed to connect to Aptos node: {}", response.status());
-                return Err(anyhow::anyhow!("Connection failed"));
+    // Test connection by getting ledger info (not needed when replaying a capture)
+    if config.replay_path.is_none() {
+        println!("config.rpc_url {}", config.rpc_url);
+        let test_url = format!("{}/", config.rpc_url);
+        match http_client.get(&test_url).send().await {
+            Ok(response) => {
+                if response.status().is_success() {
+                    println!("✅ Successfully connected to Aptos node");
+                } else {
+                    eprintln!("[FATAL INDEXER ERROR] Failed to connect to Aptos node: {}", response.status());
+                    return Err(anyhow::anyhow!("Connection failed"));
+                }
+            },
+            Err(e) => {
+                eprintln!("[FATAL INDEXER ERROR] Failed to connect to Aptos node: {}", e);
+                return Err(e.into());
             }
-        },
-        Err(e) => {
-            eprintln!("[FATAL INDEXER ERROR] Failed to connect to Aptos node: {}", e);
-            return Err(e.into());
-        }
-    };
+        };
+    }
 
     // 4. Start the two main services concurrently
     println!("🚀 Starting API Server and Blockchain Indexer...");
 
-    let api_handle = tokio::spawn(api::run_api_server(Arc::clone(&config), Arc::clone(&db)));
-    let indexer_handle = tokio::spawn(indexer::run_indexer(
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut api_handle = tokio::spawn(api::run_api_server(
         Arc::clone(&config),
         Arc::clone(&db),
         Arc::clone(&http_client),
+        shutdown_rx.clone(),
+    ));
+    let mut indexer_handle = tokio::spawn(indexer::run_indexer(
+        Arc::clone(&config),
+        Arc::clone(&db),
+        Arc::clone(&http_client),
+        shutdown_rx,
     ));
 
-    // Keep the application running and handle exits gracefully
-    tokio::select! {
-        result = api_handle => {
+    // Keep the application running and handle exits gracefully. However we stop,
+    // the remaining task is wound down first so the indexer flushes its buffer.
+    let result = tokio::select! {
+        result = &mut api_handle => {
             eprintln!("[FATAL] API server has exited.");
-            result??;
+            shutdown_tx.send_replace(true);
+            let _ = indexer_handle.await;
+            task_result(result)
         }
-        result = indexer_handle => {
+        result = &mut indexer_handle => {
             eprintln!("[FATAL] Blockchain indexer has exited.");
-            result??;
+            shutdown_tx.send_replace(true);
+            let _ = api_handle.await;
+            task_result(result)
+        }
+        _ = shutdown_signal() => {
+            println!("🛑 Shutdown signal received, stopping API server and indexer...");
+            shutdown_tx.send_replace(true);
+            let api_result = task_result(api_handle.await);
+            task_result(indexer_handle.await).and(api_result)
         }
     };
 
-    Ok(())
+    db.flush()?;
+    match db.get_last_processed_version()? {
+        Some(version) => println!("✅ Database flushed through version {}. Shutdown complete.", version),
+        None => println!("✅ Database flushed. Shutdown complete."),
+    }
+    result
 }
@@ -1,5 +1,6 @@
 // --- Position Models ---
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -16,6 +17,11 @@ pub struct Position {
     pub entry_price: String, 
     pub margin: String,      
     pub size: String,        
+    // Latest mark-to-market from PositionUpdated; None until the first update.
+    #[serde(default)]
+    pub mark_price: Option<String>,
+    #[serde(default)]
+    pub unrealized_pnl: Option<String>, // i256 as string
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +33,52 @@ pub struct HistoricalPosition {
     pub owner_address: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub enum PositionEventKind {
+    Opened,
+    Closed,
+    Liquidated,
+}
+
+// A single lifecycle event of a position, as emitted on-chain. Mark-price
+// updates are not lifecycle events; they only refresh the open `Position`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionEvent {
+    pub kind: PositionEventKind,
+    pub version: u64, // transaction version that emitted the event
+    pub index: u64,   // index of the event within that transaction
+    pub data: serde_json::Value,
+}
+
+// --- Sync Status Models ---
+
+// A ledger tip as reported by the node's ledger info.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LedgerTip {
+    pub version: u64,
+    pub timestamp_usecs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncStatus {
+    pub last_processed_version: Option<u64>,
+    pub latest_ledger_version: Option<u64>,
+    // None until both a ledger tip and a flushed cursor are known.
+    pub lag_versions: Option<u64>,
+    // Lag divided by the chain's recent throughput, from the last two tips seen.
+    pub estimated_lag_seconds: Option<f64>,
+}
+
+// Pushed to websocket subscribers whenever a position event is indexed.
+#[derive(Debug, Clone, Serialize)]
+pub struct PositionUpdate {
+    pub owner: String, // 0x-prefixed 32-byte owner key
+    pub position_id: String,
+    #[serde(flatten)]
+    pub event: PositionEvent,
+}
+
 // --- Note Models ---
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -45,6 +97,7 @@ pub struct UnspentNote {
 
 // --- Metadata Model ---
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserMetadata {
     pub last_used_nullifier_nonce: u64,
@@ -52,9 +105,33 @@ pub struct UserMetadata {
 
 // --- API Models ---
 
+// Opaque pagination cursor over a position history: the position_id of the
+// last item already returned, base64url-encoded. Histories are stored
+// newest-first and only ever prepended to, so resuming after a known row never
+// skips or repeats rows when new history lands between page requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryCursor {
+    pub last_position_id: String,
+}
+
+impl HistoryCursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.last_position_id.as_bytes())
+    }
+
+    pub fn decode(token: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(token).ok()?;
+        let last_position_id = String::from_utf8(bytes).ok()?;
+        Some(Self { last_position_id })
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct PaginatedResponse<T> {
     pub items: Vec<T>,
     pub has_more: bool,
     pub next_cursor: Option<String>,
+    // Total rows across all pages. History is stored as one list per owner that
+    // every page already loads, so this adds no extra reads.
+    pub total: Option<usize>,
 }